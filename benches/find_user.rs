@@ -0,0 +1,47 @@
+//! Compares the ordinary `find_user` path, whose statement sqlx prepares once
+//! per connection and caches, against the same query with `persistent(false)`,
+//! which is re-parsed on every call. Needs the database from `compose.yml` (or `DATABASE_URL`).
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use sqlx_playground::{
+    connect_postgres,
+    email::Email,
+    repository::{self, insert_user, User},
+    schema::run_migrations,
+};
+use tokio::runtime::Runtime;
+
+fn find_user(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let (pool, user_id) = rt.block_on(async {
+        let pool = connect_postgres().await.unwrap();
+        run_migrations(&pool).await.unwrap();
+        let email = Email::try_from(format!("bench+{}@example.com", uuid::Uuid::new_v4()).as_str())
+            .unwrap();
+        let user_id = insert_user(&pool, "Bench", &email).await.unwrap();
+        (pool, user_id)
+    });
+
+    let mut group = c.benchmark_group("find_user");
+    group.throughput(Throughput::Elements(1));
+
+    group.bench_function("prepared", |b| {
+        b.to_async(&rt)
+            .iter(|| async { repository::find_user(&pool, user_id).await.unwrap() });
+    });
+    group.bench_function("unprepared", |b| {
+        b.to_async(&rt).iter(|| async {
+            sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1 AND deleted_at IS NULL")
+                .bind(user_id)
+                .persistent(false)
+                .fetch_optional(&pool)
+                .await
+                .unwrap()
+        });
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, find_user);
+criterion_main!(benches);
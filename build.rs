@@ -0,0 +1,4 @@
+fn main() {
+    // Re-embed `sqlx::migrate!()` when a migration is added or edited.
+    println!("cargo:rerun-if-changed=migrations");
+}
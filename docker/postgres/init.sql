@@ -1,18 +1 @@
 CREATE DATABASE db;
-
-\c db;
-
-CREATE TABLE IF NOT EXISTS users (
-  id SERIAL PRIMARY KEY,
-  name VARCHAR(255) NOT NULL,
-  email VARCHAR(255) NOT NULL,
-  note VARCHAR(255),
-  is_active BOOLEAN DEFAULT TRUE
-);
-
-CREATE TABLE IF NOT EXISTS posts (
-  id SERIAL PRIMARY KEY,
-  user_id INTEGER NOT NULL,
-  content TEXT NOT NULL,
-  FOREIGN KEY (user_id) REFERENCES users(id)
-);
@@ -0,0 +1,28 @@
+CREATE TYPE user_status AS ENUM ('pending', 'active', 'suspended');
+
+CREATE TABLE users (
+  id SERIAL PRIMARY KEY,
+  name VARCHAR(255) NOT NULL,
+  email VARCHAR(255) NOT NULL UNIQUE,
+  note VARCHAR(255),
+  is_active BOOLEAN DEFAULT TRUE,
+  status user_status NOT NULL DEFAULT 'active',
+  metadata JSONB,
+  balance NUMERIC(12, 2) NOT NULL DEFAULT 0,
+  tags TEXT[],
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE posts (
+  id SERIAL PRIMARY KEY,
+  user_id INTEGER NOT NULL,
+  content TEXT NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+CREATE TABLE users_uuid (
+  id UUID PRIMARY KEY,
+  name VARCHAR(255) NOT NULL,
+  email VARCHAR(255) NOT NULL
+);
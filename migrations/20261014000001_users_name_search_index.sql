@@ -0,0 +1 @@
+CREATE INDEX users_name_search_idx ON users USING GIN (to_tsvector('english', name));
@@ -0,0 +1,6 @@
+-- Deleting a user removes their posts too. To block deleting users who still
+-- have posts instead, use `ON DELETE RESTRICT` in place of `ON DELETE CASCADE`.
+ALTER TABLE posts
+  DROP CONSTRAINT posts_user_id_fkey,
+  ADD CONSTRAINT posts_user_id_fkey
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
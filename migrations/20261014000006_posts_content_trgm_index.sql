@@ -0,0 +1,3 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX posts_content_trgm_idx ON posts USING GIN (content gin_trgm_ops);
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN registration_ip INET;
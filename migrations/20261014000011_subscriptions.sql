@@ -0,0 +1,5 @@
+CREATE TABLE subscriptions (
+  id SERIAL PRIMARY KEY,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  valid_during TSTZRANGE NOT NULL
+);
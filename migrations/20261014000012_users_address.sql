@@ -0,0 +1,6 @@
+CREATE TYPE address AS (
+  street TEXT,
+  city TEXT
+);
+
+ALTER TABLE users ADD COLUMN address address;
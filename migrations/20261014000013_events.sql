@@ -0,0 +1,11 @@
+-- NOTIFY payloads are capped at 8000 bytes, so events are stored here and only
+-- their id is sent on the `events` channel.
+CREATE TABLE events (
+  id BIGSERIAL PRIMARY KEY,
+  kind TEXT NOT NULL,
+  payload JSONB NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  processed_at TIMESTAMPTZ
+);
+
+CREATE INDEX events_unprocessed_idx ON events (id) WHERE processed_at IS NULL;
@@ -0,0 +1,18 @@
+-- Publishes every new user, whichever statement inserted it (COPY included):
+-- a `user_created` event holding the row, its id on the `events` channel and
+-- the user id on the `user_created` channel.
+CREATE FUNCTION publish_user_created() RETURNS trigger AS $$
+DECLARE
+  event_id BIGINT;
+BEGIN
+  INSERT INTO events (kind, payload) VALUES ('user_created', to_jsonb(NEW))
+    RETURNING id INTO event_id;
+  PERFORM pg_notify('user_created', NEW.id::text);
+  PERFORM pg_notify('events', event_id::text);
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER users_publish_created
+  AFTER INSERT ON users
+  FOR EACH ROW EXECUTE FUNCTION publish_user_created();
@@ -0,0 +1,77 @@
+use std::fmt;
+
+use crate::error::InvalidEmail;
+
+/// An email address that has at least been checked to look like
+/// `local@domain`, so obvious typos never reach the `users` table.
+#[derive(sqlx::Type, Debug, Clone, PartialEq, Eq)]
+#[sqlx(transparent)]
+pub struct Email(String);
+
+impl Email {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<&str> for Email {
+    type Error = InvalidEmail;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let valid = match value.split_once('@') {
+            Some((local, domain)) => {
+                !local.is_empty()
+                    && !domain.is_empty()
+                    && !domain.contains('@')
+                    && !value.contains(char::is_whitespace)
+            }
+            None => false,
+        };
+
+        if valid {
+            Ok(Self(value.to_string()))
+        } else {
+            Err(InvalidEmail(value.to_string()))
+        }
+    }
+}
+
+impl AsRef<str> for Email {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Email {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_from_ok() {
+        let email = Email::try_from("hoge@example.com").unwrap();
+
+        assert_eq!(email.as_str(), "hoge@example.com");
+    }
+
+    #[test]
+    fn try_from_invalid() {
+        for value in [
+            "notanemail",
+            "@example.com",
+            "hoge@",
+            "a@b@c",
+            "ho ge@example.com",
+        ] {
+            assert!(
+                Email::try_from(value).is_err(),
+                "{value} should be rejected"
+            );
+        }
+    }
+}
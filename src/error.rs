@@ -0,0 +1,31 @@
+#[derive(Debug, thiserror::Error)]
+pub enum UserError {
+    #[error("a user with this email already exists")]
+    DuplicateEmail,
+    #[error("user {id} not found")]
+    NotFound { id: i32 },
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0:?} is not a valid email address")]
+pub struct InvalidEmail(pub String);
+
+#[derive(Debug, thiserror::Error)]
+pub enum DbError {
+    /// Every connection stayed busy for the whole `acquire_timeout`.
+    #[error("timed out waiting for a pooled connection")]
+    PoolTimeout,
+    #[error(transparent)]
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for DbError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::PoolTimedOut => Self::PoolTimeout,
+            err => Self::Database(err),
+        }
+    }
+}
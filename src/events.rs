@@ -0,0 +1,335 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use serde_json::Value;
+use sqlx::{
+    postgres::{PgListener, PgNotification},
+    PgExecutor, Pool, Postgres,
+};
+
+use crate::retry::{is_transient, with_retry};
+
+const RECONNECT_ATTEMPTS: u32 = 5;
+
+/// Channel a trigger on `users` notifies with the id of every inserted user,
+/// whichever function or statement inserted it.
+pub const USER_CREATED_CHANNEL: &str = "user_created";
+
+/// Channel notified with the id of every row added to `events`.
+pub const EVENTS_CHANNEL: &str = "events";
+
+/// Kind of the event the same trigger publishes, with the new user row as
+/// payload.
+pub const USER_CREATED_EVENT: &str = "user_created";
+
+#[derive(sqlx::FromRow, Debug, Clone, PartialEq)]
+pub struct Event {
+    pub id: i64,
+    pub kind: String,
+    pub payload: Value,
+    pub created_at: DateTime<Utc>,
+    pub processed_at: Option<DateTime<Utc>>,
+}
+
+/// Stores an event and notifies [`EVENTS_CHANNEL`] with its id once the
+/// surrounding transaction commits. Only the id goes through NOTIFY, so
+/// `payload` is not bound by its 8000 byte limit.
+pub async fn publish_event(
+    executor: impl PgExecutor<'_>,
+    kind: &str,
+    payload: &Value,
+) -> Result<i64> {
+    Ok(sqlx::query_scalar(
+        "WITH inserted AS (INSERT INTO events (kind, payload) VALUES ($1, $2) RETURNING id) \
+         SELECT id FROM inserted, pg_notify($3, id::text)",
+    )
+    .bind(kind)
+    .bind(payload)
+    .bind(EVENTS_CHANNEL)
+    .fetch_one(executor)
+    .await?)
+}
+
+/// Listens on [`EVENTS_CHANNEL`] and yields every event published after this
+/// returns, fetching each one by the id it was notified with.
+pub async fn listen_events(pool: &Pool<Postgres>) -> Result<impl Stream<Item = Result<Event>>> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(EVENTS_CHANNEL).await?;
+
+    let pool = pool.clone();
+    Ok(listener.into_stream().then(move |notification| {
+        let pool = pool.clone();
+        async move {
+            let notification = notification?;
+            let id: i64 = notification.payload().parse().with_context(|| {
+                format!("invalid event id payload: {:?}", notification.payload())
+            })?;
+
+            Ok(
+                sqlx::query_as::<_, Event>("SELECT * FROM events WHERE id = $1")
+                    .bind(id)
+                    .fetch_one(&pool)
+                    .await?,
+            )
+        }
+    }))
+}
+
+/// Marks every unprocessed event as processed and returns them, oldest first.
+/// Events another consumer is processing concurrently are skipped rather than
+/// waited on.
+pub async fn consume_events(executor: impl PgExecutor<'_>) -> Result<Vec<Event>> {
+    let mut events = sqlx::query_as::<_, Event>(
+        "UPDATE events SET processed_at = now() \
+         WHERE id IN (SELECT id FROM events WHERE processed_at IS NULL FOR UPDATE SKIP LOCKED) \
+         RETURNING *",
+    )
+    .fetch_all(executor)
+    .await?;
+    events.sort_by_key(|event| event.id);
+
+    Ok(events)
+}
+
+/// Listens on [`USER_CREATED_CHANNEL`] and yields the id of every user
+/// inserted after this returns, including bulk inserts, upserts and COPY.
+pub async fn listen_user_events(pool: &Pool<Postgres>) -> Result<impl Stream<Item = Result<i32>>> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(USER_CREATED_CHANNEL).await?;
+
+    Ok(listener.into_stream().map(|notification| {
+        let notification = notification?;
+        notification
+            .payload()
+            .parse()
+            .with_context(|| format!("invalid user id payload: {:?}", notification.payload()))
+    }))
+}
+
+/// What [`ResilientListener::recv`] got.
+#[derive(Debug)]
+pub enum ListenerEvent {
+    Notification(PgNotification),
+    /// The connection was lost and a new one now listens on the channel.
+    /// Notifications sent in between are lost, so callers that cannot miss
+    /// any should catch up from the database, e.g. with [`consume_events`].
+    Reconnected,
+}
+
+/// A [`PgListener`] on a single channel that opens a new connection and
+/// LISTENs again whenever the current one is lost, instead of going quiet.
+pub struct ResilientListener {
+    pool: Pool<Postgres>,
+    channel: String,
+    listener: PgListener,
+}
+
+impl ResilientListener {
+    pub async fn connect(pool: &Pool<Postgres>, channel: &str) -> Result<Self> {
+        Ok(Self {
+            pool: pool.clone(),
+            channel: channel.to_string(),
+            listener: Self::listen(pool, channel).await?,
+        })
+    }
+
+    /// Waits for the next notification. When the connection drops, it
+    /// reconnects with backoff and returns [`ListenerEvent::Reconnected`];
+    /// it only fails when that does not succeed either, or on an error not
+    /// caused by losing the connection.
+    pub async fn recv(&mut self) -> Result<ListenerEvent> {
+        match self.listener.try_recv().await {
+            Ok(Some(notification)) => return Ok(ListenerEvent::Notification(notification)),
+            Ok(None) => {}
+            Err(err) => {
+                let err = anyhow::Error::from(err);
+                if !is_transient(&err) {
+                    return Err(err);
+                }
+                tracing::warn!(channel = %self.channel, error = %err, "listener connection lost");
+            }
+        }
+
+        let (pool, channel) = (&self.pool, self.channel.as_str());
+        self.listener = with_retry(RECONNECT_ATTEMPTS, || Self::listen(pool, channel)).await?;
+
+        Ok(ListenerEvent::Reconnected)
+    }
+
+    async fn listen(pool: &Pool<Postgres>, channel: &str) -> Result<PgListener> {
+        let mut listener = PgListener::connect_with(pool).await?;
+        listener.listen(channel).await?;
+
+        Ok(listener)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        repository::{copy_in_users, find_user_by_email, insert_user, upsert_user},
+        test_utils::{connect_postgres, unique_email},
+    };
+
+    #[tokio::test]
+    async fn listen_user_events_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let events = listen_user_events(&pool).await?;
+        tokio::pin!(events);
+
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+
+        // Other tests may insert users concurrently, so skip their events.
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(id) = events.next().await {
+                if id? == user_id {
+                    return Ok(());
+                }
+            }
+            anyhow::bail!("listener closed")
+        })
+        .await;
+
+        assert!(matches!(received, Ok(Ok(()))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listen_events_large_payload() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let events = listen_events(&pool).await?;
+        tokio::pin!(events);
+        let payload = serde_json::json!({ "body": "x".repeat(100_000) });
+
+        let event_id = publish_event(&pool, "large", &payload).await?;
+
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(event) = events.next().await {
+                let event = event?;
+                if event.id == event_id {
+                    return Ok(event);
+                }
+            }
+            anyhow::bail!("listener closed")
+        })
+        .await??;
+
+        assert_eq!(received.kind, "large");
+        assert_eq!(received.payload, payload);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn insert_user_publishes_event() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+
+        let user_id = insert_user(&pool, "John Doe", &email).await?;
+
+        let event: Event =
+            sqlx::query_as("SELECT * FROM events WHERE kind = $1 AND payload->>'id' = $2::text")
+                .bind(USER_CREATED_EVENT)
+                .bind(user_id)
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(event.payload["email"], email.as_str());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn every_insert_path_publishes() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let events = listen_user_events(&pool).await?;
+        tokio::pin!(events);
+
+        let copied = unique_email("hoge");
+        copy_in_users(
+            &pool,
+            [("John Doe".to_string(), copied.clone())].into_iter(),
+        )
+        .await?;
+        let copied_id = find_user_by_email(&pool, copied.as_str())
+            .await?
+            .unwrap()
+            .id;
+        let upserted_id = upsert_user(&pool, "Jane Doe", &unique_email("fuga")).await?;
+
+        let mut pending = vec![copied_id, upserted_id];
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(id) = events.next().await {
+                let id = id?;
+                pending.retain(|pending| *pending != id);
+                if pending.is_empty() {
+                    return Ok(());
+                }
+            }
+            anyhow::bail!("listener closed")
+        })
+        .await;
+        assert!(matches!(received, Ok(Ok(()))));
+
+        let event: Event =
+            sqlx::query_as("SELECT * FROM events WHERE kind = $1 AND payload->>'id' = $2::text")
+                .bind(USER_CREATED_EVENT)
+                .bind(copied_id)
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(event.payload["email"], copied.as_str());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn consume_events_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let event_id = publish_event(&pool, "test", &serde_json::json!({})).await?;
+
+        let events = consume_events(&pool).await?;
+
+        let event = events.iter().find(|event| event.id == event_id).unwrap();
+        assert!(event.processed_at.is_some());
+        assert!(consume_events(&pool)
+            .await?
+            .iter()
+            .all(|event| event.id != event_id));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resilient_listener_reconnects() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let channel = "resilient_listener_test";
+        let mut listener = ResilientListener::connect(&pool, channel).await?;
+
+        let pid: i32 = sqlx::query_scalar("SELECT pg_backend_pid()")
+            .fetch_one(&mut listener.listener)
+            .await?;
+        sqlx::query("SELECT pg_terminate_backend($1)")
+            .bind(pid)
+            .execute(&pool)
+            .await?;
+
+        let event = tokio::time::timeout(Duration::from_secs(5), listener.recv()).await??;
+        assert!(matches!(event, ListenerEvent::Reconnected));
+
+        sqlx::query("SELECT pg_notify($1, 'hello')")
+            .bind(channel)
+            .execute(&pool)
+            .await?;
+
+        let event = tokio::time::timeout(Duration::from_secs(5), listener.recv()).await??;
+        assert!(matches!(
+            event,
+            ListenerEvent::Notification(notification) if notification.payload() == "hello"
+        ));
+
+        Ok(())
+    }
+}
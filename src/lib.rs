@@ -0,0 +1,413 @@
+pub mod email;
+pub mod error;
+pub mod events;
+pub mod metrics;
+pub mod pool;
+pub mod query;
+pub mod repository;
+pub mod retry;
+pub mod schema;
+pub mod transaction;
+
+mod telemetry;
+
+#[cfg(test)]
+mod test_utils;
+
+pub use pool::{
+    connect_postgres, connect_postgres_with_config, connect_postgres_with_parts,
+    connect_postgres_with_url, PoolConfig, DEFAULT_DATABASE_URL,
+};
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use sqlx::{error::ErrorKind, Row};
+
+    use crate::{
+        repository::{insert_post, insert_user, Post, User},
+        test_utils::{connect_postgres, test_in_transaction, unique_email},
+    };
+
+    #[tokio::test]
+    async fn select_number() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let row: (i64,) = sqlx::query_as("SELECT $1")
+            .bind(150_i64)
+            .fetch_one(&pool)
+            .await?;
+
+        assert_eq!(row.0, 150_i64);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn insert_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+
+        let row = sqlx::query("INSERT INTO users (name, email) VALUES ($1, $2) RETURNING *")
+            .bind("John Doe")
+            .bind(&email)
+            .fetch_one(&pool)
+            .await?;
+
+        assert!(row.try_get::<i32, _>("id").is_ok());
+        assert_eq!(row.get::<String, _>("name"), "John Doe");
+        assert_eq!(row.get::<String, _>("email"), email.as_str());
+        assert_eq!(row.get::<Option<String>, _>("note"), None);
+        assert!(row.get::<bool, _>("is_active"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn select_one_record_ok() -> Result<()> {
+        test_in_transaction(|tx| {
+            Box::pin(async move {
+                let email = unique_email("hoge");
+                let user_id = insert_user(&mut **tx, "John Doe", &email).await?;
+
+                let row = sqlx::query("SELECT * FROM users WHERE id = $1")
+                    .bind(user_id)
+                    .fetch_one(&mut **tx)
+                    .await?;
+
+                assert!(row.try_get::<i32, _>("id").is_ok());
+                assert_eq!(row.get::<String, _>("name"), "John Doe");
+                assert_eq!(row.get::<String, _>("email"), email.as_str());
+                assert_eq!(row.get::<Option<String>, _>("note"), None);
+                assert!(row.get::<bool, _>("is_active"));
+
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn select_multi_records_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let email_1 = unique_email("hoge");
+        let email_2 = unique_email("hello");
+        let user_id_1 = insert_user(&pool, "John Doe", &email_1).await?;
+        let user_id_2 = insert_user(&pool, "Hello", &email_2).await?;
+
+        let rows = sqlx::query("SELECT * FROM users WHERE id = ANY($1)")
+            .bind(vec![user_id_1, user_id_2])
+            .fetch_all(&pool)
+            .await?;
+
+        assert_eq!(rows.len(), 2);
+        let row_1 = &rows[0];
+        assert_eq!(row_1.get::<i32, _>("id"), user_id_1);
+        assert_eq!(row_1.get::<String, _>("name"), "John Doe");
+        assert_eq!(row_1.get::<String, _>("email"), email_1.as_str());
+        assert_eq!(row_1.get::<Option<String>, _>("note"), None);
+        assert!(row_1.get::<bool, _>("is_active"));
+
+        let row_2 = &rows[1];
+        assert_eq!(row_2.get::<i32, _>("id"), user_id_2);
+        assert_eq!(row_2.get::<String, _>("name"), "Hello");
+        assert_eq!(row_2.get::<String, _>("email"), email_2.as_str());
+        assert_eq!(row_2.get::<Option<String>, _>("note"), None);
+        assert!(row_2.get::<bool, _>("is_active"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn select_not_found() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let res = sqlx::query("SELECT * FROM users WHERE id = $1")
+            .bind(-1)
+            .fetch_one(&pool)
+            .await;
+
+        assert!(matches!(res, Err(sqlx::Error::RowNotFound)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn select_from_row() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+        let user_id = insert_user(&pool, "John Doe", &email).await?;
+
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&pool)
+            .await?;
+
+        assert_eq!(user.id, user_id);
+        assert_eq!(user.name, "John Doe");
+        assert_eq!(user.email, email.as_str());
+        assert_eq!(user.note, None);
+        assert!(user.is_active);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn insert_err_null() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let res = sqlx::query("INSERT INTO users (name) VALUES ($1) RETURNING id")
+            .bind("John Doe")
+            .fetch_one(&pool)
+            .await;
+
+        assert!(
+            matches!(res, Err(sqlx::Error::Database(err)) if err.kind() == ErrorKind::NotNullViolation)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tx_commit() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+        let mut tx = pool.begin().await?;
+
+        let row = sqlx::query("INSERT INTO users (name, email) VALUES ($1, $2) RETURNING id")
+            .bind("John Doe")
+            .bind(&email)
+            .fetch_one(&mut *tx)
+            .await?;
+        let user_id: i32 = row.get("id");
+
+        tx.commit().await?;
+
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&pool)
+            .await?;
+
+        assert_eq!(user.id, user_id);
+        assert_eq!(user.name, "John Doe");
+        assert_eq!(user.email, email.as_str());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tx_explicit_rollback() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+        let mut tx = pool.begin().await?;
+
+        let row = sqlx::query("INSERT INTO users (name, email) VALUES ($1, $2) RETURNING id")
+            .bind("John Doe")
+            .bind(&email)
+            .fetch_one(&mut *tx)
+            .await?;
+        let user_id: i32 = row.get("id");
+
+        tx.rollback().await?;
+
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(&pool)
+            .await?;
+
+        assert_eq!(user, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tx_implicit_rollback() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+        let user_id: i32 = {
+            let mut tx = pool.begin().await?;
+
+            let row = sqlx::query("INSERT INTO users (name, email) VALUES ($1, $2) RETURNING id")
+                .bind("John Doe")
+                .bind(&email)
+                .fetch_one(&mut *tx)
+                .await?;
+
+            row.get("id")
+        };
+
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(&pool)
+            .await?;
+
+        assert_eq!(user, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn from_row_2() -> Result<()> {
+        #[derive(sqlx::Type, PartialEq, Debug)]
+        #[sqlx(transparent)]
+        struct UserId(i32);
+
+        #[derive(sqlx::FromRow)]
+        struct User {
+            id: UserId,
+            name: String,
+            email: String,
+            note: Option<String>,
+            is_active: bool,
+        }
+
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+        let user_id = insert_user(&pool, "John Doe", &email).await?;
+
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&pool)
+            .await?;
+
+        assert_eq!(user.id, UserId(user_id));
+        assert_eq!(user.name, "John Doe");
+        assert_eq!(user.email, email.as_str());
+        assert_eq!(user.note, None);
+        assert!(user.is_active);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn from_row_3() -> Result<()> {
+        #[allow(dead_code)]
+        #[derive(sqlx::FromRow)]
+        struct User {
+            id: i32,
+            name: String,
+            email: String,
+            note: Option<String>,
+            is_active: bool,
+            #[sqlx(skip)]
+            posts: Vec<Post>,
+        }
+
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "name", &unique_email("email")).await?;
+        insert_post(&pool, user_id, "body").await?;
+
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&pool)
+            .await?;
+
+        let posts = sqlx::query_as::<_, Post>("SELECT * FROM posts WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_all(&pool)
+            .await?;
+
+        let user = User { posts, ..user };
+
+        assert_eq!(user.posts.len(), 1);
+        Ok(())
+    }
+
+    #[allow(non_snake_case)]
+    #[tokio::test]
+    async fn from_row_rename() -> Result<()> {
+        #[derive(sqlx::FromRow)]
+        struct UserApi {
+            id: i32,
+            #[sqlx(rename = "name")]
+            displayName: String,
+            #[sqlx(rename = "is_active")]
+            isActive: bool,
+        }
+
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+
+        let user = sqlx::query_as::<_, UserApi>("SELECT * FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&pool)
+            .await?;
+
+        assert_eq!(user.id, user_id);
+        assert_eq!(user.displayName, "John Doe");
+        assert!(user.isActive);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn from_row_default() -> Result<()> {
+        #[derive(sqlx::FromRow)]
+        struct User {
+            id: i32,
+            name: String,
+            #[sqlx(default)]
+            email: String,
+            #[sqlx(default)]
+            note: Option<String>,
+        }
+
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+
+        let user = sqlx::query_as::<_, User>("SELECT id, name FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&pool)
+            .await?;
+
+        assert_eq!(user.id, user_id);
+        assert_eq!(user.name, "John Doe");
+        assert_eq!(user.email, "");
+        assert_eq!(user.note, None);
+
+        Ok(())
+    }
+
+    // Going through `&pool` may hand every query a different connection. Hold
+    // one `PoolConnection` when queries depend on the same session, like a
+    // temp table or `SET`, and to read your own writes on that connection.
+    // Release it as soon as the work is done, since it is unusable by anyone
+    // else while held.
+    #[tokio::test]
+    async fn held_connection() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut conn = pool.acquire().await?;
+
+        let pid: i32 = sqlx::query_scalar("SELECT pg_backend_pid()")
+            .fetch_one(&mut *conn)
+            .await?;
+        sqlx::query("CREATE TEMP TABLE held_connection_notes (body TEXT NOT NULL)")
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("INSERT INTO held_connection_notes (body) VALUES ($1)")
+            .bind("hello")
+            .execute(&mut *conn)
+            .await?;
+        let user_id = insert_user(&mut *conn, "John Doe", &unique_email("hoge")).await?;
+
+        let body: String = sqlx::query_scalar("SELECT body FROM held_connection_notes")
+            .fetch_one(&mut *conn)
+            .await?;
+        assert_eq!(body, "hello");
+        let name: String = sqlx::query_scalar("SELECT name FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&mut *conn)
+            .await?;
+        assert_eq!(name, "John Doe");
+        let same_pid: i32 = sqlx::query_scalar("SELECT pg_backend_pid()")
+            .fetch_one(&mut *conn)
+            .await?;
+        assert_eq!(same_pid, pid);
+
+        // The temp table would otherwise live on with the pooled connection.
+        conn.close().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+
+/// Upper bounds of the latency histogram buckets. Anything slower lands in
+/// one extra overflow bucket.
+pub const LATENCY_BUCKETS: [Duration; 7] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+];
+
+/// Query counters kept by a repository, one set per operation such as
+/// `users.insert`. Once an operation has been seen, recording is a couple of
+/// relaxed atomic increments under a read lock, so it is fine to leave on in
+/// production.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    operations: RwLock<HashMap<&'static str, Counters>>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    queries: AtomicU64,
+    errors: AtomicU64,
+    latency: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub queries: u64,
+    pub errors: u64,
+    /// Query counts per [`LATENCY_BUCKETS`] bucket, followed by the overflow
+    /// bucket.
+    pub latency: [u64; LATENCY_BUCKETS.len() + 1],
+}
+
+impl Metrics {
+    pub fn record(&self, operation: &'static str, elapsed: Duration, failed: bool) {
+        let operations = self.operations.read().unwrap();
+        match operations.get(operation) {
+            Some(counters) => counters.record(elapsed, failed),
+            None => {
+                drop(operations);
+                self.operations
+                    .write()
+                    .unwrap()
+                    .entry(operation)
+                    .or_default()
+                    .record(elapsed, failed);
+            }
+        }
+    }
+
+    /// Counters per operation, leaving out the ones that never ran.
+    pub fn snapshot(&self) -> HashMap<&'static str, MetricsSnapshot> {
+        self.operations
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(operation, counters)| (*operation, counters.snapshot()))
+            .collect()
+    }
+}
+
+impl Counters {
+    fn record(&self, elapsed: Duration, failed: bool) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| elapsed <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            queries: self.queries.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            latency: std::array::from_fn(|i| self.latency[i].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_ok() {
+        let metrics = Metrics::default();
+
+        metrics.record("users.find", Duration::from_micros(500), false);
+        metrics.record("users.find", Duration::from_millis(7), true);
+        metrics.record("users.insert", Duration::from_secs(5), false);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        let find = &snapshot["users.find"];
+        assert_eq!(find.queries, 2);
+        assert_eq!(find.errors, 1);
+        assert_eq!(find.latency, [1, 0, 1, 0, 0, 0, 0, 0]);
+        let insert = &snapshot["users.insert"];
+        assert_eq!(insert.queries, 1);
+        assert_eq!(insert.errors, 0);
+        assert_eq!(insert.latency, [0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,458 @@
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use sqlx::{
+    postgres::{PgArguments, PgColumn, PgRow},
+    Column, Either, FromRow, PgExecutor, Pool, Postgres, Row, TypeInfo, ValueRef,
+};
+use tokio_util::sync::CancellationToken;
+
+/// Runs `sql` with `params` bound to its placeholders and returns the number
+/// of affected rows.
+///
+/// Meant for one-off `UPDATE`s and `DELETE`s that are not worth a dedicated
+/// repository function.
+pub async fn execute(executor: impl PgExecutor<'_>, sql: &str, params: PgArguments) -> Result<u64> {
+    let result = sqlx::query_with(sql, params).execute(executor).await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Runs `sql` with `params` bound and decodes the first column of its single
+/// row, e.g. `let n: i64 = scalar(&pool, "SELECT COUNT(*) FROM users", params)`.
+pub async fn scalar<T>(executor: impl PgExecutor<'_>, sql: &str, params: PgArguments) -> Result<T>
+where
+    (T,): for<'r> FromRow<'r, PgRow>,
+    T: Send + Unpin,
+{
+    Ok(sqlx::query_scalar_with(sql, params)
+        .fetch_one(executor)
+        .await?)
+}
+
+/// Runs `sql` with `params` bound and decodes the first column of every row,
+/// e.g. `let emails: Vec<String> = fetch_column(&pool, "SELECT email FROM users", params)`.
+pub async fn fetch_column<T>(
+    executor: impl PgExecutor<'_>,
+    sql: &str,
+    params: PgArguments,
+) -> Result<Vec<T>>
+where
+    (T,): for<'r> FromRow<'r, PgRow>,
+    T: Send + Unpin,
+{
+    Ok(sqlx::query_scalar_with(sql, params)
+        .fetch_all(executor)
+        .await?)
+}
+
+/// [`scalar`] for `int8`/`bigint` results, such as `COUNT(*)`.
+pub async fn fetch_i64(
+    executor: impl PgExecutor<'_>,
+    sql: &str,
+    params: PgArguments,
+) -> Result<i64> {
+    scalar(executor, sql, params).await
+}
+
+/// [`scalar`] for `text`/`varchar` results.
+pub async fn fetch_string(
+    executor: impl PgExecutor<'_>,
+    sql: &str,
+    params: PgArguments,
+) -> Result<String> {
+    scalar(executor, sql, params).await
+}
+
+/// [`scalar`] for `boolean` results, such as `EXISTS(...)`.
+pub async fn fetch_bool(
+    executor: impl PgExecutor<'_>,
+    sql: &str,
+    params: PgArguments,
+) -> Result<bool> {
+    scalar(executor, sql, params).await
+}
+
+/// [`scalar`] for `timestamptz` results.
+pub async fn fetch_timestamptz(
+    executor: impl PgExecutor<'_>,
+    sql: &str,
+    params: PgArguments,
+) -> Result<DateTime<Utc>> {
+    scalar(executor, sql, params).await
+}
+
+/// Same as `fetch_all`, but gives up as soon as `token` is cancelled, e.g.
+/// because the request the query serves was dropped.
+///
+/// On cancellation the statement is aborted with `pg_cancel_backend` from
+/// another pooled connection, since Postgres keeps running it even once the
+/// client is gone. The original connection is closed instead of returned to
+/// the pool, as it may still be waiting for the aborted query.
+pub async fn fetch_with_cancel(
+    pool: &Pool<Postgres>,
+    sql: &str,
+    params: PgArguments,
+    token: &CancellationToken,
+) -> Result<Vec<PgRow>> {
+    let mut conn = pool.acquire().await?;
+    let pid: i32 = sqlx::query_scalar("SELECT pg_backend_pid()")
+        .fetch_one(&mut *conn)
+        .await?;
+
+    let rows = tokio::select! {
+        rows = sqlx::query_with(sql, params).fetch_all(&mut *conn) => Some(rows),
+        () = token.cancelled() => None,
+    };
+
+    match rows {
+        Some(rows) => Ok(rows?),
+        None => {
+            sqlx::query("SELECT pg_cancel_backend($1)")
+                .bind(pid)
+                .execute(pool)
+                .await?;
+            drop(conn.detach());
+            bail!("query cancelled");
+        }
+    }
+}
+
+/// Runs `sql` and turns its rows into a JSON array of objects keyed by column
+/// name, without a struct to decode into.
+///
+/// Integers, floats, text, booleans, JSON and NULL are supported; cast other
+/// columns to `::text` in the query.
+pub async fn fetch_json(
+    executor: impl PgExecutor<'_>,
+    sql: &str,
+    params: PgArguments,
+) -> Result<serde_json::Value> {
+    let rows = sqlx::query_with(sql, params).fetch_all(executor).await?;
+
+    let rows = rows
+        .iter()
+        .map(|row| {
+            row.columns()
+                .iter()
+                .map(|column| Ok((column.name().to_string(), column_json(row, column)?)))
+                .collect::<Result<serde_json::Map<_, _>>>()
+                .map(serde_json::Value::Object)
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(serde_json::Value::Array(rows))
+}
+
+fn column_json(row: &PgRow, column: &PgColumn) -> Result<serde_json::Value> {
+    let i = column.ordinal();
+    if row.try_get_raw(i)?.is_null() {
+        return Ok(serde_json::Value::Null);
+    }
+
+    let value = match column.type_info().name() {
+        "INT2" => row.try_get::<i16, _>(i)?.into(),
+        "INT4" => row.try_get::<i32, _>(i)?.into(),
+        "INT8" => row.try_get::<i64, _>(i)?.into(),
+        "FLOAT4" => row.try_get::<f32, _>(i)?.into(),
+        "FLOAT8" => row.try_get::<f64, _>(i)?.into(),
+        "TEXT" | "VARCHAR" | "BPCHAR" | "NAME" => row.try_get::<String, _>(i)?.into(),
+        "BOOL" => row.try_get::<bool, _>(i)?.into(),
+        "JSON" | "JSONB" => row.try_get::<serde_json::Value, _>(i)?,
+        other => bail!(
+            "column {:?} has unsupported type {other}, cast it to text",
+            column.name()
+        ),
+    };
+
+    Ok(value)
+}
+
+/// Returns the plan Postgres picks for `sql`, with actual timings and buffer
+/// usage, one plan line per text line.
+///
+/// `ANALYZE` really runs the statement, so explaining an `UPDATE` or `DELETE`
+/// applies it too; wrap those in a transaction that is rolled back.
+pub async fn explain(
+    executor: impl PgExecutor<'_>,
+    sql: &str,
+    params: PgArguments,
+) -> Result<String> {
+    let lines: Vec<String> = sqlx::query_scalar_with(
+        &format!("EXPLAIN (ANALYZE, BUFFERS, FORMAT TEXT) {sql}"),
+        params,
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(lines.join("\n"))
+}
+
+/// Runs every statement in `sql` and collects the rows of each one into its
+/// own result set, in order. Statements without rows, like `UPDATE`, yield an
+/// empty set.
+///
+/// Multiple statements only work over the simple query protocol, so `sql`
+/// cannot have bind parameters.
+pub async fn fetch_result_sets<'e>(
+    executor: impl PgExecutor<'e>,
+    sql: &'e str,
+) -> Result<Vec<Vec<PgRow>>> {
+    let mut results = executor.fetch_many(sql);
+    let mut sets = Vec::new();
+    let mut current = Vec::new();
+
+    while let Some(result) = results.try_next().await? {
+        match result {
+            Either::Left(_) => sets.push(std::mem::take(&mut current)),
+            Either::Right(row) => current.push(row),
+        }
+    }
+
+    Ok(sets)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use chrono::TimeZone;
+    use sqlx::{Arguments, Encode, Type};
+
+    use super::*;
+    use crate::{
+        repository::{insert_post, insert_user},
+        test_utils::{connect_postgres, unique_email},
+    };
+
+    #[tokio::test]
+    async fn execute_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        insert_post(&pool, user_id, "first").await?;
+        insert_post(&pool, user_id, "second").await?;
+
+        let mut params = PgArguments::default();
+        params.add(user_id);
+        let rows_affected = execute(&pool, "DELETE FROM posts WHERE user_id = $1", params).await?;
+
+        assert_eq!(rows_affected, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scalar_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+
+        let count: i64 =
+            scalar(&pool, "SELECT COUNT(*) FROM users", PgArguments::default()).await?;
+        assert!(count >= 1);
+
+        let mut params = PgArguments::default();
+        params.add(user_id);
+        let exists: bool = scalar(
+            &pool,
+            "SELECT EXISTS(SELECT 1 FROM users WHERE id = $1)",
+            params,
+        )
+        .await?;
+        assert!(exists);
+
+        Ok(())
+    }
+
+    fn params(value: impl for<'q> Encode<'q, Postgres> + Type<Postgres> + Send) -> PgArguments {
+        let mut params = PgArguments::default();
+        params.add(value);
+        params
+    }
+
+    #[tokio::test]
+    async fn fetch_i64_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let value = fetch_i64(&pool, "SELECT $1::int8", params(i64::MAX)).await?;
+
+        assert_eq!(value, i64::MAX);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_string_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let value = fetch_string(&pool, "SELECT $1::text", params("こんにちは, world")).await?;
+
+        assert_eq!(value, "こんにちは, world");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_bool_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        assert!(fetch_bool(&pool, "SELECT $1::boolean", params(true)).await?);
+        assert!(!fetch_bool(&pool, "SELECT NOT $1::boolean", params(true)).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_timestamptz_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let at = Utc.with_ymd_and_hms(2024, 2, 29, 12, 34, 56).unwrap();
+
+        let value = fetch_timestamptz(&pool, "SELECT $1::timestamptz", params(at)).await?;
+
+        assert_eq!(value, at);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_with_cancel_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let token = CancellationToken::new();
+
+        let rows = fetch_with_cancel(&pool, "SELECT 1", PgArguments::default(), &token).await?;
+
+        assert_eq!(rows.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_with_cancel_cancelled() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            canceller.cancel();
+        });
+
+        let start = Instant::now();
+        let res = fetch_with_cancel(
+            &pool,
+            "SELECT pg_sleep(10) /* fetch_with_cancel_cancelled */",
+            PgArguments::default(),
+            &token,
+        )
+        .await;
+
+        assert!(res.is_err());
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // The cancel request is handled asynchronously by the server.
+        let mut running = 0;
+        for _ in 0..20 {
+            running = scalar::<i64>(
+                &pool,
+                "SELECT COUNT(*) FROM pg_stat_activity WHERE state = 'active' \
+                 AND pid <> pg_backend_pid() AND query LIKE '%fetch_with_cancel_cancelled%'",
+                PgArguments::default(),
+            )
+            .await?;
+            if running == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(running, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_result_sets_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let sets = fetch_result_sets(&pool, "SELECT 1; SELECT 2 UNION ALL SELECT 3").await?;
+
+        let values: Vec<Vec<i32>> = sets
+            .iter()
+            .map(|rows| rows.iter().map(|row| row.get(0)).collect())
+            .collect();
+        assert_eq!(values, vec![vec![1], vec![2, 3]]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn explain_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let plan = explain(&pool, "SELECT * FROM users WHERE id = $1", params(1)).await?;
+
+        assert!(
+            plan.contains("Index Scan") || plan.contains("Seq Scan"),
+            "unexpected plan: {plan}"
+        );
+        assert!(plan.contains("Execution Time"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_json_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+        let user_id = insert_user(&pool, "John Doe", &email).await?;
+
+        let json = fetch_json(
+            &pool,
+            "SELECT id, name, email, note, is_active, balance::text FROM users WHERE id = $1",
+            params(user_id),
+        )
+        .await?;
+
+        assert_eq!(
+            json,
+            serde_json::json!([{
+                "id": user_id,
+                "name": "John Doe",
+                "email": email.as_str(),
+                "note": null,
+                "is_active": true,
+                "balance": "0.00",
+            }])
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_json_unsupported_type() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let res = fetch_json(&pool, "SELECT now() AS at", PgArguments::default()).await;
+
+        assert!(res.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_column_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id_1 = insert_user(&pool, "John Doe", &unique_email("user1")).await?;
+        let user_id_2 = insert_user(&pool, "Jane Doe", &unique_email("user2")).await?;
+
+        let mut params = PgArguments::default();
+        params.add([user_id_2, user_id_1]);
+        let ids: Vec<i32> = fetch_column(
+            &pool,
+            "SELECT id FROM users WHERE id = ANY($1) ORDER BY id",
+            params,
+        )
+        .await?;
+
+        assert_eq!(ids, vec![user_id_1, user_id_2]);
+
+        Ok(())
+    }
+}
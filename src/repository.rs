@@ -0,0 +1,2691 @@
+use std::{collections::HashMap, future::Future, net::IpAddr, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use futures::{Stream, TryStreamExt};
+use rust_decimal::Decimal;
+use serde_json::Value;
+use sqlx::{
+    error::ErrorKind,
+    postgres::{
+        types::{PgInterval, PgRange},
+        PgArguments, PgRow,
+    },
+    types::Json,
+    Acquire, FromRow, PgExecutor, Pool, Postgres, QueryBuilder, Row, Transaction,
+};
+use uuid::Uuid;
+
+use crate::{
+    email::Email, error::UserError, metrics::Metrics, pool::ReplicatedPool, telemetry::traced,
+};
+
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[sqlx(type_name = "user_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum UserStatus {
+    Pending,
+    Active,
+    Suspended,
+}
+
+/// Maps the `address` composite type.
+#[derive(sqlx::Type, Debug, Clone, PartialEq, serde::Serialize)]
+#[sqlx(type_name = "address")]
+pub struct Address {
+    pub street: String,
+    pub city: String,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone, PartialEq, serde::Serialize)]
+pub struct User {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+    pub note: Option<String>,
+    pub is_active: bool,
+    pub status: UserStatus,
+    pub metadata: Option<Value>,
+    pub balance: Decimal,
+    pub tags: Option<Vec<String>>,
+    pub created_at: DateTime<Utc>,
+    /// Refreshed by a trigger on every update, including raw SQL ones.
+    pub updated_at: DateTime<Utc>,
+    /// Where the user signed up from, for auditing.
+    pub registration_ip: Option<IpAddr>,
+    pub avatar: Option<Vec<u8>>,
+    pub address: Option<Address>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub version: i32,
+}
+
+/// A user keyed by a client-generated UUID instead of a serial id.
+#[derive(sqlx::FromRow, Debug, PartialEq)]
+pub struct UserUuid {
+    pub id: Uuid,
+    pub name: String,
+    pub email: String,
+}
+
+#[derive(sqlx::FromRow, Debug, PartialEq, serde::Deserialize)]
+pub struct Post {
+    pub id: i32,
+    pub user_id: i32,
+    pub content: String,
+}
+
+/// Inserts a user. Like every insert into `users`, a trigger publishes it on
+/// [`crate::events::USER_CREATED_CHANNEL`] and as a
+/// [`crate::events::USER_CREATED_EVENT`] event.
+///
+/// Fails with [`UserError::DuplicateEmail`] when `email` is already taken.
+pub async fn insert_user(
+    executor: impl PgExecutor<'_>,
+    name: &str,
+    email: &Email,
+) -> Result<i32, UserError> {
+    let row = sqlx::query("INSERT INTO users (name, email) VALUES ($1, $2) RETURNING id")
+        .bind(name)
+        .bind(email)
+        .fetch_one(executor)
+        .await
+        .map_err(map_unique_violation)?;
+
+    Ok(row.get("id"))
+}
+
+/// Same as [`insert_user`], but returns `None` instead of failing when `email`
+/// is already taken, leaving the existing row untouched.
+pub async fn insert_user_if_absent(
+    executor: impl PgExecutor<'_>,
+    name: &str,
+    email: &Email,
+) -> Result<Option<i32>> {
+    let row = sqlx::query(
+        "INSERT INTO users (name, email) VALUES ($1, $2) ON CONFLICT (email) DO NOTHING RETURNING id",
+    )
+    .bind(name)
+    .bind(email)
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(row.map(|row| row.get("id")))
+}
+
+fn map_unique_violation(err: sqlx::Error) -> UserError {
+    match err {
+        sqlx::Error::Database(err) if err.kind() == ErrorKind::UniqueViolation => {
+            UserError::DuplicateEmail
+        }
+        err => UserError::Database(err),
+    }
+}
+
+/// Inserts a user with a `note`, which is stored as SQL `NULL` when `None`.
+pub async fn insert_user_with_note(
+    executor: impl PgExecutor<'_>,
+    name: &str,
+    email: &Email,
+    note: Option<&str>,
+) -> Result<i32> {
+    let row = sqlx::query("INSERT INTO users (name, email, note) VALUES ($1, $2, $3) RETURNING id")
+        .bind(name)
+        .bind(email)
+        .bind(note)
+        .fetch_one(executor)
+        .await?;
+
+    Ok(row.get("id"))
+}
+
+pub async fn insert_user_with_status(
+    executor: impl PgExecutor<'_>,
+    name: &str,
+    email: &Email,
+    status: UserStatus,
+) -> Result<i32> {
+    let row =
+        sqlx::query("INSERT INTO users (name, email, status) VALUES ($1, $2, $3) RETURNING id")
+            .bind(name)
+            .bind(email)
+            .bind(status)
+            .fetch_one(executor)
+            .await?;
+
+    Ok(row.get("id"))
+}
+
+/// Replaces the user's JSONB metadata, clearing it when `metadata` is `None`.
+pub async fn set_user_metadata(
+    executor: impl PgExecutor<'_>,
+    id: i32,
+    metadata: Option<&Value>,
+) -> Result<u64> {
+    let result = sqlx::query("UPDATE users SET metadata = $1 WHERE id = $2")
+        .bind(metadata.map(Json))
+        .bind(id)
+        .execute(executor)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn set_user_balance(
+    executor: impl PgExecutor<'_>,
+    id: i32,
+    balance: Decimal,
+) -> Result<u64> {
+    let result = sqlx::query("UPDATE users SET balance = $1 WHERE id = $2")
+        .bind(balance)
+        .bind(id)
+        .execute(executor)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Replaces the user's tags. `Some(&[])` stores an empty array while `None`
+/// stores NULL.
+pub async fn set_user_tags(
+    executor: impl PgExecutor<'_>,
+    id: i32,
+    tags: Option<&[&str]>,
+) -> Result<u64> {
+    let result = sqlx::query("UPDATE users SET tags = $1 WHERE id = $2")
+        .bind(tags)
+        .bind(id)
+        .execute(executor)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn set_registration_ip(
+    executor: impl PgExecutor<'_>,
+    id: i32,
+    ip: IpAddr,
+) -> Result<u64> {
+    let result = sqlx::query("UPDATE users SET registration_ip = $1 WHERE id = $2")
+        .bind(ip)
+        .bind(id)
+        .execute(executor)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn set_user_address(
+    executor: impl PgExecutor<'_>,
+    id: i32,
+    address: &Address,
+) -> Result<u64> {
+    let result = sqlx::query("UPDATE users SET address = $1 WHERE id = $2")
+        .bind(address)
+        .bind(id)
+        .execute(executor)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Stores the user's profile image as raw bytes.
+pub async fn set_avatar(executor: impl PgExecutor<'_>, id: i32, bytes: &[u8]) -> Result<u64> {
+    let result = sqlx::query("UPDATE users SET avatar = $1 WHERE id = $2")
+        .bind(bytes)
+        .bind(id)
+        .execute(executor)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Stores `length` as the user's `session_length`. Intervals keep whole
+/// microseconds, so anything finer is truncated.
+pub async fn set_session_length(
+    executor: impl PgExecutor<'_>,
+    id: i32,
+    length: Duration,
+) -> Result<u64> {
+    let length = Duration::new(length.as_secs(), length.subsec_micros() * 1_000);
+    let result = sqlx::query("UPDATE users SET session_length = $1 WHERE id = $2")
+        .bind(PgInterval::try_from(length).map_err(|err| anyhow!(err))?)
+        .bind(id)
+        .execute(executor)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Reads the user's `session_length` interval, `None` when it is NULL or no
+/// user has `id`.
+///
+/// Fails for intervals with a month part like `'1 mon'`, since a month has no
+/// fixed length. Days are taken as 24 hours.
+pub async fn session_length(executor: impl PgExecutor<'_>, id: i32) -> Result<Option<Duration>> {
+    let interval: Option<PgInterval> =
+        sqlx::query_scalar("SELECT session_length FROM users WHERE id = $1 AND deleted_at IS NULL")
+            .bind(id)
+            .fetch_optional(executor)
+            .await?
+            .flatten();
+
+    interval.map(interval_to_duration).transpose()
+}
+
+fn interval_to_duration(interval: PgInterval) -> Result<Duration> {
+    if interval.months != 0 {
+        bail!(
+            "interval has {} months, which have no fixed length",
+            interval.months
+        );
+    }
+
+    let micros = i64::from(interval.days)
+        .checked_mul(24 * 60 * 60 * 1_000_000)
+        .and_then(|micros| micros.checked_add(interval.microseconds))
+        .ok_or_else(|| anyhow!("interval of {} days is too long", interval.days))?;
+    let micros = u64::try_from(micros).map_err(|_| anyhow!("interval is negative"))?;
+
+    Ok(Duration::from_micros(micros))
+}
+
+/// Inserts `(name, email)` pairs in a single statement by binding them as two
+/// parallel arrays, returning the generated ids in input order.
+pub async fn insert_users_bulk(
+    executor: impl PgExecutor<'_>,
+    users: &[(String, Email)],
+) -> Result<Vec<i32>> {
+    let (names, emails): (Vec<&str>, Vec<&str>) = users
+        .iter()
+        .map(|(name, email)| (name.as_str(), email.as_str()))
+        .unzip();
+
+    // RETURNING comes back in no particular order, so the ids are matched
+    // back to their input position through the unique email.
+    let rows = sqlx::query(
+        "WITH input AS (SELECT * FROM UNNEST($1::text[], $2::text[]) WITH ORDINALITY AS t(name, email, ord)), \
+         inserted AS (INSERT INTO users (name, email) SELECT name, email FROM input RETURNING id, email) \
+         SELECT inserted.id FROM inserted JOIN input USING (email) ORDER BY input.ord",
+    )
+    .bind(names)
+    .bind(emails)
+    .fetch_all(executor)
+    .await?;
+
+    Ok(rows.iter().map(|row| row.get("id")).collect())
+}
+
+/// Same as [`insert_users_bulk`], but spells the rows out as a multi-row
+/// `VALUES` list with two bind parameters each. Postgres caps a statement at
+/// 65535 parameters, so keep batches under about 32k rows.
+///
+/// An empty `users` returns no ids without querying.
+pub async fn insert_users_values(
+    executor: impl PgExecutor<'_>,
+    users: &[(String, Email)],
+) -> Result<Vec<i32>> {
+    if users.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut query = QueryBuilder::<Postgres>::new("INSERT INTO users (name, email) ");
+    query.push_values(users, |mut row, (name, email)| {
+        row.push_bind(name).push_bind(email);
+    });
+    query.push(" RETURNING id");
+
+    Ok(query.build_query_scalar().fetch_all(executor).await?)
+}
+
+/// Inserts each `(name, email)` pair in its own savepoint of one
+/// transaction, so a failing row, e.g. a [`UserError::DuplicateEmail`], is
+/// reported in its slot of the returned outcomes instead of aborting the
+/// batch. The rows that succeeded are committed.
+pub async fn insert_users_lenient(
+    pool: &Pool<Postgres>,
+    users: &[(String, Email)],
+) -> Result<Vec<Result<i32, UserError>>> {
+    let mut tx = pool.begin().await?;
+    let mut outcomes = Vec::with_capacity(users.len());
+
+    for (name, email) in users {
+        let mut savepoint = tx.begin().await?;
+        match insert_user(&mut *savepoint, name, email).await {
+            Ok(id) => {
+                savepoint.commit().await?;
+                outcomes.push(Ok(id));
+            }
+            Err(err) => {
+                savepoint.rollback().await?;
+                outcomes.push(Err(err));
+            }
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(outcomes)
+}
+
+/// Streams `(name, email)` pairs into `users` through the COPY protocol,
+/// returning the number of copied rows.
+pub async fn copy_in_users(
+    pool: &Pool<Postgres>,
+    rows: impl Iterator<Item = (String, Email)>,
+) -> Result<u64> {
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    let mut conn = pool.acquire().await?;
+    let mut copy = conn
+        .copy_in_raw("COPY users (name, email) FROM STDIN (FORMAT csv)")
+        .await?;
+
+    let mut buf = String::with_capacity(CHUNK_SIZE);
+    for (name, email) in rows {
+        buf.push_str(&csv_field(&name));
+        buf.push(',');
+        buf.push_str(&csv_field(email.as_str()));
+        buf.push('\n');
+
+        if buf.len() >= CHUNK_SIZE {
+            copy.send(buf.as_bytes()).await?;
+            buf.clear();
+        }
+    }
+    if !buf.is_empty() {
+        copy.send(buf.as_bytes()).await?;
+    }
+
+    Ok(copy.finish().await?)
+}
+
+/// Quotes a CSV field so commas, quotes and newlines are taken literally.
+fn csv_field(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+/// Inserts a user, or renames the existing one with the same email.
+///
+/// The returned id is stable across calls with the same email.
+pub async fn upsert_user(executor: impl PgExecutor<'_>, name: &str, email: &Email) -> Result<i32> {
+    let row = sqlx::query(
+        "INSERT INTO users (name, email) VALUES ($1, $2) ON CONFLICT (email) DO UPDATE SET name = EXCLUDED.name RETURNING id",
+    )
+    .bind(name)
+    .bind(email)
+    .fetch_one(executor)
+    .await?;
+
+    Ok(row.get("id"))
+}
+
+/// Returns `None` when no user has `id`.
+///
+/// Like every single-row lookup here, this goes through `fetch_optional`, so a
+/// missing row is `Ok(None)` and never [`sqlx::Error::RowNotFound`]; only real
+/// failures come back as errors. [`find_user_required`] is the one exception,
+/// for callers that want a missing user to be an error.
+///
+/// sqlx queries are persistent by default, so each connection prepares this
+/// statement once and reuses it on later calls.
+pub async fn find_user(executor: impl PgExecutor<'_>, id: i32) -> Result<Option<User>> {
+    Ok(
+        sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1 AND deleted_at IS NULL")
+            .bind(id)
+            .fetch_optional(executor)
+            .await?,
+    )
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UserSummary {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+}
+
+/// A user's contact details, with `full_contact` formatted as
+/// `name <email>` while decoding instead of being stored.
+#[derive(Debug, PartialEq)]
+pub struct UserContact {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+    pub full_contact: String,
+}
+
+// Written by hand, since the derive can only copy columns into fields.
+impl FromRow<'_, PgRow> for UserContact {
+    fn from_row(row: &PgRow) -> sqlx::Result<Self> {
+        let id = row.try_get("id")?;
+        let name: String = row.try_get("name")?;
+        let email: String = row.try_get("email")?;
+        let full_contact = format!("{name} <{email}>");
+
+        Ok(Self {
+            id,
+            name,
+            email,
+            full_contact,
+        })
+    }
+}
+
+pub async fn find_user_contact(
+    executor: impl PgExecutor<'_>,
+    id: i32,
+) -> Result<Option<UserContact>> {
+    Ok(sqlx::query_as::<_, UserContact>(
+        "SELECT id, name, email FROM users WHERE id = $1 AND deleted_at IS NULL",
+    )
+    .bind(id)
+    .fetch_optional(executor)
+    .await?)
+}
+
+/// Same as [`find_user`], but also finds soft deleted users.
+pub async fn find_user_including_deleted(
+    executor: impl PgExecutor<'_>,
+    id: i32,
+) -> Result<Option<User>> {
+    Ok(
+        sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+            .bind(id)
+            .fetch_optional(executor)
+            .await?,
+    )
+}
+
+/// Looks a user up by email, ignoring case.
+pub async fn find_user_by_email(
+    executor: impl PgExecutor<'_>,
+    email: &str,
+) -> Result<Option<User>> {
+    Ok(sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE lower(email) = lower($1) AND deleted_at IS NULL",
+    )
+    .bind(email)
+    .fetch_optional(executor)
+    .await?)
+}
+
+/// Same as [`find_user`], but checked against the schema at compile time, so a
+/// typo in a column name fails the build instead of the query.
+pub async fn find_user_checked(
+    executor: impl PgExecutor<'_>,
+    id: i32,
+) -> Result<Option<UserSummary>> {
+    Ok(sqlx::query_as!(
+        UserSummary,
+        "SELECT id, name, email FROM users WHERE id = $1 AND deleted_at IS NULL",
+        id
+    )
+    .fetch_optional(executor)
+    .await?)
+}
+
+/// Same as [`find_user`], but fails with [`UserError::NotFound`] when no user
+/// has `id`.
+pub async fn find_user_required(executor: impl PgExecutor<'_>, id: i32) -> Result<User, UserError> {
+    sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1 AND deleted_at IS NULL")
+        .bind(id)
+        .fetch_optional(executor)
+        .await?
+        .ok_or(UserError::NotFound { id })
+}
+
+/// Checks whether a user has `id` without fetching its row.
+pub async fn user_exists(executor: impl PgExecutor<'_>, id: i32) -> Result<bool> {
+    Ok(sqlx::query_scalar(
+        "SELECT EXISTS(SELECT 1 FROM users WHERE id = $1 AND deleted_at IS NULL)",
+    )
+    .bind(id)
+    .fetch_one(executor)
+    .await?)
+}
+
+/// Fetches the users with the given ids keyed by id. Ids without a user are
+/// absent from the map.
+pub async fn find_users_by_ids(
+    executor: impl PgExecutor<'_>,
+    ids: &[i32],
+) -> Result<HashMap<i32, User>> {
+    let users =
+        sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ANY($1) AND deleted_at IS NULL")
+            .bind(ids)
+            .fetch_all(executor)
+            .await?;
+
+    Ok(users.into_iter().map(|user| (user.id, user)).collect())
+}
+
+/// Same as [`find_users_by_ids`], but for id sets too large to bind as one
+/// array: the ids are COPYed into a temporary table that is joined instead.
+/// Users come back ordered by id.
+pub async fn find_users_bulk(pool: &Pool<Postgres>, ids: &[i32]) -> Result<Vec<User>> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("CREATE TEMP TABLE wanted_user_ids (id INTEGER NOT NULL) ON COMMIT DROP")
+        .execute(&mut *tx)
+        .await?;
+
+    let mut copy = tx
+        .copy_in_raw("COPY wanted_user_ids (id) FROM STDIN")
+        .await?;
+    let buf: String = ids.iter().map(|id| format!("{id}\n")).collect();
+    copy.send(buf.as_bytes()).await?;
+    copy.finish().await?;
+
+    let users = sqlx::query_as::<_, User>(
+        "SELECT users.* FROM users \
+         JOIN (SELECT DISTINCT id FROM wanted_user_ids) wanted ON wanted.id = users.id \
+         WHERE users.deleted_at IS NULL ORDER BY users.id",
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(users)
+}
+
+/// Same as [`find_users_by_ids`], but returns one entry per id in `ids`, in
+/// the same order, with `None` for ids without a user.
+pub async fn find_users_ordered(
+    executor: impl PgExecutor<'_>,
+    ids: &[i32],
+) -> Result<Vec<Option<User>>> {
+    let users = find_users_by_ids(executor, ids).await?;
+
+    Ok(ids.iter().map(|id| users.get(id).cloned()).collect())
+}
+
+pub async fn all_users(executor: impl PgExecutor<'_>) -> Result<Vec<User>> {
+    Ok(
+        sqlx::query_as::<_, User>("SELECT * FROM users WHERE deleted_at IS NULL ORDER BY id")
+            .fetch_all(executor)
+            .await?,
+    )
+}
+
+/// Returns the number of updated rows, which is `0` when no user has `id`.
+pub async fn update_user(
+    executor: impl PgExecutor<'_>,
+    id: i32,
+    name: &str,
+    email: &Email,
+) -> Result<u64> {
+    let result = sqlx::query("UPDATE users SET name = $1, email = $2 WHERE id = $3")
+        .bind(name)
+        .bind(email)
+        .bind(id)
+        .execute(executor)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Same as [`update_user`], but hands back the updated row, or `None` when no
+/// user has `id` or it is soft deleted. Soft deleted users are left as is.
+pub async fn update_user_returning(
+    executor: impl PgExecutor<'_>,
+    id: i32,
+    name: &str,
+    email: &Email,
+) -> Result<Option<User>> {
+    Ok(sqlx::query_as::<_, User>(
+        "UPDATE users SET name = $1, email = $2 WHERE id = $3 AND deleted_at IS NULL RETURNING *",
+    )
+    .bind(name)
+    .bind(email)
+    .bind(id)
+    .fetch_optional(executor)
+    .await?)
+}
+
+/// Runs an `UPDATE users ... RETURNING *` with `params` bound and decodes the
+/// updated users, so the affected row count is just their `len()`.
+pub async fn update_returning(
+    executor: impl PgExecutor<'_>,
+    sql: &str,
+    params: PgArguments,
+) -> Result<Vec<User>> {
+    Ok(sqlx::query_as_with::<_, User, _>(sql, params)
+        .fetch_all(executor)
+        .await?)
+}
+
+/// Same as [`update_user`], but only applies when the user is still at
+/// `expected_version`, bumping it on success.
+///
+/// Returns `false` when someone else updated the user first, or it does not
+/// exist.
+pub async fn update_user_versioned(
+    executor: impl PgExecutor<'_>,
+    id: i32,
+    name: &str,
+    email: &Email,
+    expected_version: i32,
+) -> Result<bool> {
+    let result = sqlx::query(
+        "UPDATE users SET name = $1, email = $2, version = version + 1 WHERE id = $3 AND version = $4",
+    )
+    .bind(name)
+    .bind(email)
+    .bind(id)
+    .bind(expected_version)
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Deletes the user; their posts go with them through the cascading
+/// `posts.user_id` foreign key.
+///
+/// Returns `false` when no user has `id`. With `dry_run`, nothing is deleted
+/// and the result tells whether the user would have been.
+pub async fn delete_user(executor: impl PgExecutor<'_>, id: i32, dry_run: bool) -> Result<bool> {
+    if dry_run {
+        return Ok(
+            sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM users WHERE id = $1)")
+                .bind(id)
+                .fetch_one(executor)
+                .await?,
+        );
+    }
+
+    let result = sqlx::query("DELETE FROM users WHERE id = $1")
+        .bind(id)
+        .execute(executor)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Streams every user ordered by id, decoding rows as they arrive instead of
+/// buffering the whole table.
+pub fn stream_users<'e>(
+    executor: impl PgExecutor<'e> + 'e,
+) -> impl Stream<Item = Result<User>> + 'e {
+    sqlx::query_as::<_, User>("SELECT * FROM users WHERE deleted_at IS NULL ORDER BY id")
+        .fetch(executor)
+        .map_err(Into::into)
+}
+
+/// Streams every user ordered by id and hands them to `f` in batches of
+/// `chunk_size`, the last one possibly smaller, so memory stays bounded
+/// however large the table is.
+pub async fn process_users_in_chunks<'e, F, Fut>(
+    executor: impl PgExecutor<'e> + 'e,
+    chunk_size: usize,
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(Vec<User>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    if chunk_size == 0 {
+        bail!("chunk_size must be positive");
+    }
+
+    let chunks = stream_users(executor).try_chunks(chunk_size);
+    tokio::pin!(chunks);
+    while let Some(chunk) = chunks.try_next().await.map_err(|err| err.1)? {
+        f(chunk).await?;
+    }
+
+    Ok(())
+}
+
+/// Lists users ordered by id ascending, so paging through them is
+/// deterministic. Negative `limit` and `offset` are clamped to `0`.
+pub async fn list_users(
+    executor: impl PgExecutor<'_>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<User>> {
+    Ok(sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE deleted_at IS NULL ORDER BY id LIMIT $1 OFFSET $2",
+    )
+    .bind(limit.max(0))
+    .bind(offset.max(0))
+    .fetch_all(executor)
+    .await?)
+}
+
+/// Lists up to `limit` users whose id is greater than `after_id`, ordered by id
+/// ascending. Pass `None` for the first page and the last returned id after.
+pub async fn list_users_after(
+    executor: impl PgExecutor<'_>,
+    after_id: Option<i32>,
+    limit: i64,
+) -> Result<Vec<User>> {
+    Ok(sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE deleted_at IS NULL AND ($1::int IS NULL OR id > $1) ORDER BY id LIMIT $2",
+    )
+    .bind(after_id)
+    .bind(limit.max(0))
+    .fetch_all(executor)
+    .await?)
+}
+
+pub async fn count_users(executor: impl PgExecutor<'_>) -> Result<i64> {
+    let row: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE deleted_at IS NULL")
+        .fetch_one(executor)
+        .await?;
+
+    Ok(row.0)
+}
+
+pub async fn list_active_users(executor: impl PgExecutor<'_>) -> Result<Vec<User>> {
+    Ok(sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE is_active = true AND deleted_at IS NULL ORDER BY id",
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+/// Returns `false` when no user has `id`.
+pub async fn set_user_active(executor: impl PgExecutor<'_>, id: i32, active: bool) -> Result<bool> {
+    let result = sqlx::query("UPDATE users SET is_active = $1 WHERE id = $2")
+        .bind(active)
+        .bind(id)
+        .execute(executor)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Deactivates every user in `ids` in one statement, returning how many rows
+/// changed. An empty `ids` returns `0` without querying.
+///
+/// With `dry_run`, nothing is updated and the count is how many rows would
+/// have changed.
+pub async fn deactivate_users(
+    executor: impl PgExecutor<'_>,
+    ids: &[i32],
+    dry_run: bool,
+) -> Result<u64> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    if dry_run {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE id = ANY($1)")
+            .bind(ids)
+            .fetch_one(executor)
+            .await?;
+        return Ok(count as u64);
+    }
+
+    let result = sqlx::query("UPDATE users SET is_active = false WHERE id = ANY($1)")
+        .bind(ids)
+        .execute(executor)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+#[derive(sqlx::FromRow, Debug, PartialEq)]
+pub struct UserWithPosts {
+    pub id: i32,
+    pub name: String,
+    pub email: String,
+    #[sqlx(json)]
+    pub posts: Vec<Post>,
+}
+
+/// Fetches every user together with their posts in a single query. Users
+/// without posts get an empty `posts`.
+pub async fn users_with_posts(executor: impl PgExecutor<'_>) -> Result<Vec<UserWithPosts>> {
+    Ok(sqlx::query_as::<_, UserWithPosts>(
+        "SELECT users.id, users.name, users.email, \
+         COALESCE(json_agg(posts ORDER BY posts.id) FILTER (WHERE posts.id IS NOT NULL), '[]') AS posts \
+         FROM users LEFT JOIN posts ON posts.user_id = users.id \
+         WHERE users.deleted_at IS NULL \
+         GROUP BY users.id ORDER BY users.id",
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+/// The columns of [`Post`] aliased with a `post_` prefix, so they do not
+/// collide with the `users` columns when both tables are joined.
+#[derive(sqlx::FromRow)]
+pub struct PrefixedPost {
+    #[sqlx(rename = "post_id")]
+    id: i32,
+    #[sqlx(rename = "post_user_id")]
+    user_id: i32,
+    #[sqlx(rename = "post_content")]
+    content: String,
+}
+
+impl From<PrefixedPost> for Post {
+    fn from(post: PrefixedPost) -> Self {
+        Self {
+            id: post.id,
+            user_id: post.user_id,
+            content: post.content,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow, Debug, PartialEq)]
+pub struct UserWithPost {
+    #[sqlx(flatten)]
+    pub user: User,
+    #[sqlx(flatten, try_from = "PrefixedPost")]
+    pub post: Post,
+}
+
+/// Fetches a post together with its author.
+pub async fn find_user_with_post(
+    executor: impl PgExecutor<'_>,
+    post_id: i32,
+) -> Result<Option<UserWithPost>> {
+    Ok(sqlx::query_as::<_, UserWithPost>(
+        "SELECT users.*, posts.id AS post_id, posts.user_id AS post_user_id, posts.content AS post_content \
+         FROM posts JOIN users ON users.id = posts.user_id WHERE posts.id = $1 AND users.deleted_at IS NULL",
+    )
+    .bind(post_id)
+    .fetch_optional(executor)
+    .await?)
+}
+
+pub async fn insert_user_uuid(
+    executor: impl PgExecutor<'_>,
+    id: Uuid,
+    name: &str,
+    email: &Email,
+) -> Result<Uuid> {
+    let row =
+        sqlx::query("INSERT INTO users_uuid (id, name, email) VALUES ($1, $2, $3) RETURNING id")
+            .bind(id)
+            .bind(name)
+            .bind(email)
+            .fetch_one(executor)
+            .await?;
+
+    Ok(row.get("id"))
+}
+
+pub async fn find_user_uuid(executor: impl PgExecutor<'_>, id: Uuid) -> Result<Option<UserUuid>> {
+    Ok(
+        sqlx::query_as::<_, UserUuid>("SELECT * FROM users_uuid WHERE id = $1")
+            .bind(id)
+            .fetch_optional(executor)
+            .await?,
+    )
+}
+
+/// One page of a listing along with what is needed to render pagination.
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    pub has_more: bool,
+}
+
+/// Same as [`list_users`], but also counts all users to fill in the [`Page`].
+pub async fn list_users_paged(
+    conn: impl Acquire<'_, Database = Postgres>,
+    limit: i64,
+    offset: i64,
+) -> Result<Page<User>> {
+    let mut conn = conn.acquire().await?;
+
+    let total = count_users(&mut *conn).await?;
+    let items = list_users(&mut *conn, limit, offset).await?;
+    let has_more = offset.max(0) + (items.len() as i64) < total;
+
+    Ok(Page {
+        items,
+        total,
+        has_more,
+    })
+}
+
+/// Full-text searches user names. Every word in `query` has to match, in any
+/// case and after english stemming.
+pub async fn search_users(executor: impl PgExecutor<'_>, query: &str) -> Result<Vec<User>> {
+    Ok(sqlx::query_as::<_, User>(
+        "SELECT * FROM users \
+         WHERE deleted_at IS NULL AND to_tsvector('english', name) @@ plainto_tsquery('english', $1) ORDER BY id",
+    )
+    .bind(query)
+    .fetch_all(executor)
+    .await?)
+}
+
+/// Lists users whose name starts with `prefix`, ignoring case, ordered by
+/// name. `%` and `_` in `prefix` match literally.
+pub async fn users_by_name_prefix(
+    executor: impl PgExecutor<'_>,
+    prefix: &str,
+    limit: i64,
+) -> Result<Vec<User>> {
+    Ok(sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE deleted_at IS NULL AND name ILIKE ($1 || '%') ORDER BY name, id LIMIT $2",
+    )
+    .bind(escape_like(prefix))
+    .bind(limit.max(0))
+    .fetch_all(executor)
+    .await?)
+}
+
+/// Escapes the `LIKE` wildcards with the default `\` escape character.
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Lists users matching only the filters that were set, with every value
+/// bound as a parameter rather than spliced into the SQL.
+#[derive(Debug, Clone, Default)]
+pub struct UserQuery {
+    name_like: Option<String>,
+    is_active: Option<bool>,
+    limit: Option<i64>,
+}
+
+impl UserQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps users whose name matches the `ILIKE` pattern, e.g. `"%doe%"`.
+    pub fn name_like(mut self, pattern: &str) -> Self {
+        self.name_like = Some(pattern.to_string());
+        self
+    }
+
+    pub fn is_active(mut self, active: bool) -> Self {
+        self.is_active = Some(active);
+        self
+    }
+
+    /// Negative limits are clamped to `0`.
+    pub fn limit(mut self, limit: i64) -> Self {
+        self.limit = Some(limit.max(0));
+        self
+    }
+
+    fn build(&self) -> QueryBuilder<'_, Postgres> {
+        let mut query = QueryBuilder::new("SELECT * FROM users WHERE deleted_at IS NULL");
+        if let Some(pattern) = &self.name_like {
+            query.push(" AND name ILIKE ").push_bind(pattern);
+        }
+        if let Some(active) = self.is_active {
+            query.push(" AND is_active = ").push_bind(active);
+        }
+        query.push(" ORDER BY id");
+        if let Some(limit) = self.limit {
+            query.push(" LIMIT ").push_bind(limit);
+        }
+
+        query
+    }
+
+    /// Runs the query, returning matching users ordered by id.
+    pub async fn fetch_all(&self, executor: impl PgExecutor<'_>) -> Result<Vec<User>> {
+        Ok(self
+            .build()
+            .build_query_as::<User>()
+            .fetch_all(executor)
+            .await?)
+    }
+}
+
+/// Marks the user as deleted while keeping the row for auditing. Every other
+/// read query skips soft deleted users, and their posts with them.
+///
+/// Returns `false` when no user has `id` or it is already deleted.
+pub async fn soft_delete_user(executor: impl PgExecutor<'_>, id: i32) -> Result<bool> {
+    let result =
+        sqlx::query("UPDATE users SET deleted_at = now() WHERE id = $1 AND deleted_at IS NULL")
+            .bind(id)
+            .execute(executor)
+            .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn insert_post(
+    executor: impl PgExecutor<'_>,
+    user_id: i32,
+    content: &str,
+) -> Result<i32> {
+    Ok(sqlx::query_scalar!(
+        "INSERT INTO posts (user_id, content) VALUES ($1, $2) RETURNING id",
+        user_id,
+        content
+    )
+    .fetch_one(executor)
+    .await?)
+}
+
+/// Lists the user's posts, oldest first, or none when the user is soft
+/// deleted.
+pub async fn posts_by_user(executor: impl PgExecutor<'_>, user_id: i32) -> Result<Vec<Post>> {
+    Ok(sqlx::query_as::<_, Post>(
+        "SELECT posts.* FROM posts JOIN users ON users.id = posts.user_id \
+             WHERE posts.user_id = $1 AND users.deleted_at IS NULL ORDER BY posts.id",
+    )
+    .bind(user_id)
+    .fetch_all(executor)
+    .await?)
+}
+
+/// Returns `false` when no post has `id`.
+pub async fn delete_post(executor: impl PgExecutor<'_>, id: i32) -> Result<bool> {
+    let result = sqlx::query("DELETE FROM posts WHERE id = $1")
+        .bind(id)
+        .execute(executor)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Fuzzy searches post contents with trigram similarity, so typos still
+/// match. Only posts at least `threshold` similar (`0.0..=1.0`) to `q` are
+/// returned, most similar first.
+///
+/// The threshold is what `set_limit` would set, but scoped to a transaction so
+/// it does not stick to the pooled connection.
+pub async fn search_posts(
+    conn: impl Acquire<'_, Database = Postgres>,
+    q: &str,
+    threshold: f32,
+) -> Result<Vec<Post>> {
+    let mut tx = conn.begin().await?;
+
+    sqlx::query("SELECT set_config('pg_trgm.similarity_threshold', $1, true)")
+        .bind(threshold.to_string())
+        .execute(&mut *tx)
+        .await?;
+    let posts = sqlx::query_as::<_, Post>(
+        "SELECT posts.* FROM posts JOIN users ON users.id = posts.user_id \
+         WHERE content % $1 AND users.deleted_at IS NULL \
+         ORDER BY similarity(content, $1) DESC, posts.id",
+    )
+    .bind(q)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(posts)
+}
+
+/// Returns `(user_id, post_count)` pairs; users without posts and soft
+/// deleted ones are left out.
+pub async fn post_counts_by_user(executor: impl PgExecutor<'_>) -> Result<Vec<(i32, i64)>> {
+    Ok(sqlx::query_as(
+        "SELECT user_id, COUNT(*) FROM posts JOIN users ON users.id = posts.user_id \
+             WHERE users.deleted_at IS NULL GROUP BY user_id ORDER BY user_id",
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+#[derive(sqlx::FromRow, Debug, PartialEq)]
+pub struct Subscription {
+    pub id: i32,
+    pub user_id: i32,
+    pub valid_during: PgRange<DateTime<Utc>>,
+}
+
+pub async fn insert_subscription(
+    executor: impl PgExecutor<'_>,
+    user_id: i32,
+    valid_during: PgRange<DateTime<Utc>>,
+) -> Result<i32> {
+    Ok(sqlx::query_scalar(
+        "INSERT INTO subscriptions (user_id, valid_during) VALUES ($1, $2) RETURNING id",
+    )
+    .bind(user_id)
+    .bind(valid_during)
+    .fetch_one(executor)
+    .await?)
+}
+
+/// Lists the user's subscriptions whose validity period contains the current
+/// time.
+pub async fn current_subscriptions(
+    executor: impl PgExecutor<'_>,
+    user_id: i32,
+) -> Result<Vec<Subscription>> {
+    Ok(sqlx::query_as::<_, Subscription>(
+        "SELECT * FROM subscriptions WHERE user_id = $1 AND valid_during @> now() ORDER BY id",
+    )
+    .bind(user_id)
+    .fetch_all(executor)
+    .await?)
+}
+
+/// The user operations callers need, so they can take `impl UserStore` and
+/// be tested against an in-memory fake instead of [`UsersRepository`].
+pub trait UserStore {
+    fn insert(
+        &self,
+        name: &str,
+        email: &Email,
+    ) -> impl Future<Output = Result<i32, UserError>> + Send;
+
+    fn find(&self, id: i32) -> impl Future<Output = Result<Option<User>>> + Send;
+
+    /// Users ordered by id, skipping the first `offset`.
+    fn list(&self, limit: i64, offset: i64) -> impl Future<Output = Result<Vec<User>>> + Send;
+}
+
+pub struct UsersRepository {
+    pool: ReplicatedPool,
+    metrics: Metrics,
+}
+
+impl UsersRepository {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        Self::with_replicas(ReplicatedPool::new(pool, vec![]))
+    }
+
+    /// Sends reads to the replicas of `pool` and writes to its primary.
+    pub fn with_replicas(pool: ReplicatedPool) -> Self {
+        Self {
+            pool,
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// Counters for every query this repository has run so far.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    #[tracing::instrument(name = "users.insert", skip(self, email))]
+    pub async fn insert(&self, name: &str, email: &Email) -> Result<i32, UserError> {
+        traced(
+            &self.metrics,
+            "users.insert",
+            insert_user(self.pool.write(), name, email),
+        )
+        .await
+    }
+
+    #[tracing::instrument(name = "users.upsert", skip(self, email))]
+    pub async fn upsert(&self, name: &str, email: &Email) -> Result<i32> {
+        traced(
+            &self.metrics,
+            "users.upsert",
+            upsert_user(self.pool.write(), name, email),
+        )
+        .await
+    }
+
+    #[tracing::instrument(name = "users.find", skip(self))]
+    pub async fn find(&self, id: i32) -> Result<Option<User>> {
+        traced(&self.metrics, "users.find", find_user(self.pool.read(), id)).await
+    }
+
+    #[tracing::instrument(name = "users.update", skip(self, email))]
+    pub async fn update(&self, id: i32, name: &str, email: &Email) -> Result<u64> {
+        traced(
+            &self.metrics,
+            "users.update",
+            update_user(self.pool.write(), id, name, email),
+        )
+        .await
+    }
+
+    #[tracing::instrument(name = "users.delete", skip(self))]
+    pub async fn delete(&self, id: i32) -> Result<bool> {
+        traced(
+            &self.metrics,
+            "users.delete",
+            delete_user(self.pool.write(), id, false),
+        )
+        .await
+    }
+
+    #[tracing::instrument(name = "users.all", skip(self))]
+    pub async fn all(&self) -> Result<Vec<User>> {
+        traced(&self.metrics, "users.all", all_users(self.pool.read())).await
+    }
+
+    #[tracing::instrument(name = "users.count", skip(self))]
+    pub async fn count(&self) -> Result<i64> {
+        traced(&self.metrics, "users.count", count_users(self.pool.read())).await
+    }
+
+    #[tracing::instrument(name = "users.list", skip(self))]
+    pub async fn list(&self, limit: i64, offset: i64) -> Result<Vec<User>> {
+        traced(
+            &self.metrics,
+            "users.list",
+            list_users(self.pool.read(), limit, offset),
+        )
+        .await
+    }
+}
+
+impl UserStore for UsersRepository {
+    async fn insert(&self, name: &str, email: &Email) -> Result<i32, UserError> {
+        UsersRepository::insert(self, name, email).await
+    }
+
+    async fn find(&self, id: i32) -> Result<Option<User>> {
+        UsersRepository::find(self, id).await
+    }
+
+    async fn list(&self, limit: i64, offset: i64) -> Result<Vec<User>> {
+        UsersRepository::list(self, limit, offset).await
+    }
+}
+
+pub struct PostsRepository {
+    pool: ReplicatedPool,
+    metrics: Metrics,
+}
+
+impl PostsRepository {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        Self::with_replicas(ReplicatedPool::new(pool, vec![]))
+    }
+
+    /// Sends reads to the replicas of `pool` and writes to its primary.
+    pub fn with_replicas(pool: ReplicatedPool) -> Self {
+        Self {
+            pool,
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// Counters for every query this repository has run so far.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    #[tracing::instrument(name = "posts.insert", skip(self, content))]
+    pub async fn insert(&self, user_id: i32, content: &str) -> Result<i32> {
+        traced(
+            &self.metrics,
+            "posts.insert",
+            insert_post(self.pool.write(), user_id, content),
+        )
+        .await
+    }
+
+    #[tracing::instrument(name = "posts.by_user", skip(self))]
+    pub async fn by_user(&self, user_id: i32) -> Result<Vec<Post>> {
+        traced(
+            &self.metrics,
+            "posts.by_user",
+            posts_by_user(self.pool.read(), user_id),
+        )
+        .await
+    }
+
+    #[tracing::instrument(name = "posts.delete", skip(self))]
+    pub async fn delete(&self, id: i32) -> Result<bool> {
+        traced(
+            &self.metrics,
+            "posts.delete",
+            delete_post(self.pool.write(), id),
+        )
+        .await
+    }
+}
+
+/// Runs repository calls inside a caller-owned transaction, so several of
+/// them commit or roll back together.
+pub struct TxRepository<'t, 'c> {
+    tx: &'t mut Transaction<'c, Postgres>,
+}
+
+impl<'t, 'c> TxRepository<'t, 'c> {
+    pub fn new(tx: &'t mut Transaction<'c, Postgres>) -> Self {
+        Self { tx }
+    }
+
+    pub async fn insert(&mut self, name: &str, email: &Email) -> Result<i32, UserError> {
+        insert_user(&mut **self.tx, name, email).await
+    }
+
+    pub async fn find(&mut self, id: i32) -> Result<Option<User>> {
+        find_user(&mut **self.tx, id).await
+    }
+
+    pub async fn update(&mut self, id: i32, name: &str, email: &Email) -> Result<u64> {
+        update_user(&mut **self.tx, id, name, email).await
+    }
+
+    pub async fn insert_post(&mut self, user_id: i32, content: &str) -> Result<i32> {
+        insert_post(&mut **self.tx, user_id, content).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Mutex};
+
+    use chrono::SubsecRound;
+    use futures::StreamExt;
+    use sqlx::Arguments;
+
+    use super::*;
+    use crate::test_utils::{begin_with_empty_tables, connect_postgres, unique_email};
+
+    /// A [`UserStore`] keeping users in a `Vec`, no database involved.
+    #[derive(Default)]
+    struct FakeUserStore {
+        users: Mutex<Vec<User>>,
+    }
+
+    impl UserStore for FakeUserStore {
+        async fn insert(&self, name: &str, email: &Email) -> Result<i32, UserError> {
+            let mut users = self.users.lock().unwrap();
+            if users.iter().any(|user| user.email == email.as_str()) {
+                return Err(UserError::DuplicateEmail);
+            }
+
+            let id = users.len() as i32 + 1;
+            let now = Utc::now();
+            users.push(User {
+                id,
+                name: name.to_string(),
+                email: email.to_string(),
+                note: None,
+                is_active: true,
+                status: UserStatus::Active,
+                metadata: None,
+                balance: Decimal::ZERO,
+                tags: None,
+                created_at: now,
+                updated_at: now,
+                registration_ip: None,
+                avatar: None,
+                address: None,
+                deleted_at: None,
+                version: 0,
+            });
+
+            Ok(id)
+        }
+
+        async fn find(&self, id: i32) -> Result<Option<User>> {
+            let users = self.users.lock().unwrap();
+            Ok(users.iter().find(|user| user.id == id).cloned())
+        }
+
+        async fn list(&self, limit: i64, offset: i64) -> Result<Vec<User>> {
+            let users = self.users.lock().unwrap();
+            Ok(users
+                .iter()
+                .skip(offset.max(0) as usize)
+                .take(limit.max(0) as usize)
+                .cloned()
+                .collect())
+        }
+    }
+
+    /// Stands in for application code that only knows about [`UserStore`].
+    async fn register(store: &impl UserStore, name: &str, email: &Email) -> Result<User> {
+        let id = store.insert(name, email).await?;
+        store
+            .find(id)
+            .await?
+            .ok_or_else(|| anyhow!("user {id} not found"))
+    }
+
+    #[tokio::test]
+    async fn user_store_fake() -> Result<()> {
+        let store = FakeUserStore::default();
+        let email = unique_email("hoge");
+
+        let user = register(&store, "John Doe", &email).await?;
+        register(&store, "Jane Doe", &unique_email("fuga")).await?;
+
+        assert_eq!(user.name, "John Doe");
+        assert!(register(&store, "John Doe", &email).await.is_err());
+        assert_eq!(store.list(10, 1).await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn user_store_postgres() -> Result<()> {
+        let repo = UsersRepository::new(connect_postgres().await?);
+
+        let user = register(&repo, "John Doe", &unique_email("hoge")).await?;
+
+        assert_eq!(user.name, "John Doe");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_repository_metrics() -> Result<()> {
+        let repo = UsersRepository::new(connect_postgres().await?);
+
+        for _ in 0..3 {
+            repo.insert("John Doe", &unique_email("hoge")).await?;
+        }
+        let email = unique_email("fuga");
+        repo.insert("John Doe", &email).await?;
+        assert!(repo.insert("Jane Doe", &email).await.is_err());
+
+        repo.find(-1).await?;
+
+        let snapshot = repo.metrics().snapshot();
+        let insert = &snapshot["users.insert"];
+        assert_eq!(insert.queries, 5);
+        assert_eq!(insert.errors, 1);
+        assert_eq!(insert.latency.iter().sum::<u64>(), 5);
+        let find = &snapshot["users.find"];
+        assert_eq!(find.queries, 1);
+        assert_eq!(find.errors, 0);
+        assert!(!snapshot.contains_key("users.list"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_repository_ok() -> Result<()> {
+        let repo = UsersRepository::new(connect_postgres().await?);
+
+        let email = unique_email("hoge");
+        let user_id = repo.insert("John Doe", &email).await?;
+
+        let user = repo
+            .find(user_id)
+            .await?
+            .expect("inserted user should exist");
+        assert_eq!(user.name, "John Doe");
+        assert_eq!(user.email, email.as_str());
+
+        let users = repo.all().await?;
+        assert!(users.iter().any(|user| user.id == user_id));
+
+        assert_eq!(repo.find(-1).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn insert_user_duplicate_email() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+        insert_user(&pool, "John Doe", &email).await?;
+
+        let res = insert_user(&pool, "Jane Doe", &email).await;
+
+        assert!(matches!(res, Err(UserError::DuplicateEmail)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn insert_user_if_absent_conflict() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+
+        let id = insert_user_if_absent(&pool, "John Doe", &email).await?;
+        let again = insert_user_if_absent(&pool, "Jane Doe", &email).await?;
+
+        let user = find_user(&pool, id.unwrap()).await?.unwrap();
+        assert_eq!(user.name, "John Doe");
+        assert_eq!(again, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_user_by_email_mixed_case() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+        let user_id = insert_user(&pool, "John Doe", &email).await?;
+
+        let user = find_user_by_email(&pool, &email.as_str().to_uppercase()).await?;
+
+        assert_eq!(user.map(|user| user.id), Some(user_id));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_user_checked_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+        let user_id = insert_user(&pool, "John Doe", &email).await?;
+
+        let user = find_user_checked(&pool, user_id).await?.unwrap();
+
+        assert_eq!(user.id, user_id);
+        assert_eq!(user.name, "John Doe");
+        assert_eq!(user.email, email.as_str());
+        assert!(find_user_checked(&pool, -1).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn user_exists_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+
+        assert!(user_exists(&pool, user_id).await?);
+        assert!(!user_exists(&pool, -1).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lookups_missing_row_none() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        assert_eq!(find_user(&pool, -1).await?, None);
+        assert_eq!(find_user_including_deleted(&pool, -1).await?, None);
+        assert_eq!(
+            find_user_by_email(&pool, "missing@example.invalid").await?,
+            None
+        );
+        assert_eq!(find_user_checked(&pool, -1).await?, None);
+        assert_eq!(find_user_with_post(&pool, -1).await?, None);
+        assert_eq!(find_user_uuid(&pool, Uuid::new_v4()).await?, None);
+        assert_eq!(UsersRepository::new(pool).find(-1).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_user_contact_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+        let user_id = insert_user(&pool, "John Doe", &email).await?;
+
+        let contact = find_user_contact(&pool, user_id).await?.unwrap();
+
+        assert_eq!(contact.full_contact, format!("John Doe <{email}>"));
+        assert_eq!(find_user_contact(&pool, -1).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_user_required_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+
+        let user = find_user_required(&pool, user_id).await?;
+
+        assert_eq!(user.id, user_id);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_user_required_not_found() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let res = find_user_required(&pool, -1).await;
+
+        assert!(matches!(res, Err(UserError::NotFound { id: -1 })));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_users_by_ids_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id_1 = insert_user(&pool, "John Doe", &unique_email("user1")).await?;
+        let user_id_2 = insert_user(&pool, "Jane Doe", &unique_email("user2")).await?;
+
+        let users = find_users_by_ids(&pool, &[user_id_1, user_id_2, -1]).await?;
+
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[&user_id_1].name, "John Doe");
+        assert_eq!(users[&user_id_2].name, "Jane Doe");
+        assert!(!users.contains_key(&-1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_users_bulk_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id_1 = insert_user(&pool, "John Doe", &unique_email("user1")).await?;
+        let user_id_2 = insert_user(&pool, "Jane Doe", &unique_email("user2")).await?;
+
+        let mut ids: Vec<i32> = (1..=20_000).map(|i| -i).collect();
+        ids.push(user_id_2);
+        ids.push(user_id_1);
+        ids.push(user_id_1);
+
+        let users = find_users_bulk(&pool, &ids).await?;
+
+        let ids: Vec<i32> = users.iter().map(|user| user.id).collect();
+        assert_eq!(ids, vec![user_id_1, user_id_2]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_users_ordered_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id_1 = insert_user(&pool, "John Doe", &unique_email("user1")).await?;
+        let user_id_2 = insert_user(&pool, "Jane Doe", &unique_email("user2")).await?;
+
+        let users = find_users_ordered(&pool, &[user_id_2, -1, user_id_1]).await?;
+
+        let ids: Vec<Option<i32>> = users
+            .iter()
+            .map(|user| user.as_ref().map(|u| u.id))
+            .collect();
+        assert_eq!(ids, vec![Some(user_id_2), None, Some(user_id_1)]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn insert_user_created_at() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+
+        let user = find_user(&pool, user_id).await?.unwrap();
+        let elapsed = Utc::now() - user.created_at;
+        assert!(
+            elapsed.num_seconds().abs() < 5,
+            "created_at was {elapsed} ago"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn insert_user_with_note_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let without_note =
+            insert_user_with_note(&pool, "John Doe", &unique_email("hoge"), None).await?;
+        let with_note =
+            insert_user_with_note(&pool, "Jane Doe", &unique_email("fuga"), Some("hi")).await?;
+
+        let user = find_user(&pool, without_note).await?.unwrap();
+        assert_eq!(user.note, None);
+        let user = find_user(&pool, with_note).await?.unwrap();
+        assert_eq!(user.note.as_deref(), Some("hi"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn insert_user_with_status_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let user_id = insert_user_with_status(
+            &pool,
+            "John Doe",
+            &unique_email("hoge"),
+            UserStatus::Suspended,
+        )
+        .await?;
+
+        let user = find_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.status, UserStatus::Suspended);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn insert_user_default_status() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+
+        let user = find_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.status, UserStatus::Active);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_user_metadata_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        let metadata = serde_json::json!({"tier": "gold", "limits": {"posts": [1, 2]}});
+
+        set_user_metadata(&pool, user_id, Some(&metadata)).await?;
+
+        let user = find_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.metadata, Some(metadata));
+
+        set_user_metadata(&pool, user_id, None).await?;
+
+        let user = find_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.metadata, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_user_balance_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        let balance = Decimal::from_str("123.45")?;
+
+        set_user_balance(&pool, user_id, balance).await?;
+
+        let user = find_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.balance, balance);
+        assert_eq!(user.balance.to_string(), "123.45");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_user_tags_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+
+        let user = find_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.tags, None);
+
+        set_user_tags(&pool, user_id, Some(&["a", "b"])).await?;
+        let user = find_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.tags, Some(vec!["a".to_string(), "b".to_string()]));
+
+        set_user_tags(&pool, user_id, Some(&[])).await?;
+        let user = find_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.tags, Some(vec![]));
+
+        set_user_tags(&pool, user_id, None).await?;
+        let user = find_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.tags, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn soft_delete_user_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+
+        assert!(soft_delete_user(&pool, user_id).await?);
+
+        assert_eq!(find_user(&pool, user_id).await?, None);
+        let user = find_user_including_deleted(&pool, user_id).await?.unwrap();
+        assert!(user.deleted_at.is_some());
+        assert!(!all_users(&pool)
+            .await?
+            .iter()
+            .any(|user| user.id == user_id));
+
+        assert!(!soft_delete_user(&pool, user_id).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn soft_deleted_user_posts_and_updates() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        insert_post(&pool, user_id, "soft deleted author's post").await?;
+        soft_delete_user(&pool, user_id).await?;
+
+        assert!(posts_by_user(&pool, user_id).await?.is_empty());
+        assert!(!post_counts_by_user(&pool)
+            .await?
+            .iter()
+            .any(|(id, _)| *id == user_id));
+        assert_eq!(
+            update_user_returning(&pool, user_id, "Jane Doe", &unique_email("fuga")).await?,
+            None
+        );
+        let user = find_user_including_deleted(&pool, user_id).await?.unwrap();
+        assert_eq!(user.name, "John Doe");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upsert_user_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+
+        let user_id_1 = upsert_user(&pool, "John Doe", &email).await?;
+        let user_id_2 = upsert_user(&pool, "Jane Doe", &email).await?;
+
+        assert_eq!(user_id_1, user_id_2);
+        let user = find_user(&pool, user_id_1).await?.unwrap();
+        assert_eq!(user.name, "Jane Doe");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_user_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        let email = unique_email("fuga");
+
+        let rows_affected = update_user(&pool, user_id, "Jane Doe", &email).await?;
+
+        assert_eq!(rows_affected, 1);
+        let user = find_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.name, "Jane Doe");
+        assert_eq!(user.email, email.as_str());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_user_returning_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        let email = unique_email("fuga");
+
+        let user = update_user_returning(&pool, user_id, "Jane Doe", &email)
+            .await?
+            .unwrap();
+        assert_eq!(user.id, user_id);
+        assert_eq!(user.name, "Jane Doe");
+        assert_eq!(user.email, email.as_str());
+
+        assert_eq!(
+            update_user_returning(&pool, -1, "Jane Doe", &email).await?,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_returning_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let john = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        let jane = insert_user(&pool, "Jane Doe", &unique_email("fuga")).await?;
+        let mut params = PgArguments::default();
+        params.add(vec![john, jane]);
+
+        let users = update_returning(
+            &pool,
+            "UPDATE users SET is_active = false WHERE id = ANY($1) RETURNING *",
+            params,
+        )
+        .await?;
+
+        let mut ids: Vec<_> = users.iter().map(|user| user.id).collect();
+        ids.sort();
+        assert_eq!(ids, [john, jane]);
+        assert!(users.iter().all(|user| !user.is_active));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_user_refreshes_updated_at() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+
+        sqlx::query("UPDATE users SET name = 'Jane Doe' WHERE id = $1")
+            .bind(user_id)
+            .execute(&pool)
+            .await?;
+
+        let user = find_user(&pool, user_id).await?.unwrap();
+        assert!(user.updated_at > user.created_at);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_user_not_found() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let rows_affected =
+            update_user(&pool, -1, "Jane Doe", &Email::try_from("fuga@example.com")?).await?;
+
+        assert_eq!(rows_affected, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_user_versioned_conflict() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+        let user_id = insert_user(&pool, "John Doe", &email).await?;
+        let version = find_user(&pool, user_id).await?.unwrap().version;
+
+        let first = update_user_versioned(&pool, user_id, "Jane Doe", &email, version).await?;
+        let second = update_user_versioned(&pool, user_id, "Jim Doe", &email, version).await?;
+
+        assert!(first);
+        assert!(!second);
+        let user = find_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.name, "Jane Doe");
+        assert_eq!(user.version, version + 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_user_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        insert_post(&pool, user_id, "body").await?;
+
+        assert!(delete_user(&pool, user_id, true).await?);
+        assert!(find_user(&pool, user_id).await?.is_some());
+
+        assert!(delete_user(&pool, user_id, false).await?);
+        assert_eq!(find_user(&pool, user_id).await?, None);
+
+        assert!(!delete_user(&pool, user_id, false).await?);
+        assert!(!delete_user(&pool, user_id, true).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_user_cascades_to_posts() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        let post_id = insert_post(&pool, user_id, "body").await?;
+
+        sqlx::query("DELETE FROM users WHERE id = $1")
+            .bind(user_id)
+            .execute(&pool)
+            .await?;
+
+        let remaining: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM posts WHERE id = $1")
+            .bind(post_id)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(remaining, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_users_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut tx = begin_with_empty_tables(&pool).await?;
+        for i in 0..3 {
+            insert_user(&mut *tx, "John Doe", &unique_email(&format!("user{i}"))).await?;
+        }
+
+        let mut stream = stream_users(&mut *tx);
+        let mut count = 0;
+        while let Some(user) = stream.next().await {
+            user?;
+            count += 1;
+        }
+
+        assert_eq!(count, 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn process_users_in_chunks_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut tx = begin_with_empty_tables(&pool).await?;
+        for i in 0..5 {
+            insert_user(&mut *tx, "John Doe", &unique_email(&format!("user{i}"))).await?;
+        }
+
+        let mut sizes = vec![];
+        process_users_in_chunks(&mut *tx, 2, |users| {
+            sizes.push(users.len());
+            async { Ok(()) }
+        })
+        .await?;
+
+        assert_eq!(sizes, vec![2, 2, 1]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_users_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut tx = begin_with_empty_tables(&pool).await?;
+        insert_user(&mut *tx, "User 1", &unique_email("user1")).await?;
+        let user_id_2 = insert_user(&mut *tx, "User 2", &unique_email("user2")).await?;
+        let user_id_3 = insert_user(&mut *tx, "User 3", &unique_email("user3")).await?;
+
+        let users = list_users(&mut *tx, 2, 1).await?;
+
+        let ids: Vec<i32> = users.iter().map(|user| user.id).collect();
+        assert_eq!(ids, vec![user_id_2, user_id_3]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_active_users_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut tx = begin_with_empty_tables(&pool).await?;
+        let active_id = insert_user(&mut *tx, "User 1", &unique_email("user1")).await?;
+        let inactive_id = insert_user(&mut *tx, "User 2", &unique_email("user2")).await?;
+
+        assert!(set_user_active(&mut *tx, inactive_id, false).await?);
+        assert!(!set_user_active(&mut *tx, -1, false).await?);
+
+        let users = list_active_users(&mut *tx).await?;
+
+        let ids: Vec<i32> = users.iter().map(|user| user.id).collect();
+        assert_eq!(ids, vec![active_id]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deactivate_users_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id_1 = insert_user(&pool, "User 1", &unique_email("user1")).await?;
+        let user_id_2 = insert_user(&pool, "User 2", &unique_email("user2")).await?;
+        let user_id_3 = insert_user(&pool, "User 3", &unique_email("user3")).await?;
+
+        let would_affect = deactivate_users(&pool, &[user_id_1, user_id_3], true).await?;
+        assert_eq!(would_affect, 2);
+        assert!(find_user(&pool, user_id_1).await?.unwrap().is_active);
+        assert!(find_user(&pool, user_id_3).await?.unwrap().is_active);
+
+        let rows_affected = deactivate_users(&pool, &[user_id_1, user_id_3], false).await?;
+
+        assert_eq!(rows_affected, 2);
+        assert!(!find_user(&pool, user_id_1).await?.unwrap().is_active);
+        assert!(find_user(&pool, user_id_2).await?.unwrap().is_active);
+        assert!(!find_user(&pool, user_id_3).await?.unwrap().is_active);
+
+        assert_eq!(deactivate_users(&pool, &[], false).await?, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn user_query_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut tx = begin_with_empty_tables(&pool).await?;
+        let john_id = insert_user(&mut *tx, "John Doe", &unique_email("user1")).await?;
+        let jane_id = insert_user(&mut *tx, "Jane Doe", &unique_email("user2")).await?;
+        let jim_id = insert_user(&mut *tx, "Jim Doe", &unique_email("user3")).await?;
+        insert_user(&mut *tx, "Alice Smith", &unique_email("user4")).await?;
+        set_user_active(&mut *tx, jane_id, false).await?;
+
+        let users = UserQuery::new()
+            .name_like("%doe")
+            .is_active(true)
+            .fetch_all(&mut *tx)
+            .await?;
+        let ids: Vec<i32> = users.iter().map(|user| user.id).collect();
+        assert_eq!(ids, vec![john_id, jim_id]);
+
+        let users = UserQuery::new().limit(1).fetch_all(&mut *tx).await?;
+        assert_eq!(users.len(), 1);
+
+        let users = UserQuery::new()
+            .name_like("'; DROP TABLE users; --")
+            .fetch_all(&mut *tx)
+            .await?;
+        assert!(users.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_users_paged_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut tx = begin_with_empty_tables(&pool).await?;
+        for i in 0..3 {
+            insert_user(&mut *tx, "John Doe", &unique_email(&format!("user{i}"))).await?;
+        }
+
+        let page = list_users_paged(&mut *tx, 2, 0).await?;
+        assert_eq!(page.items.len(), 2);
+        assert_eq!(page.total, 3);
+        assert!(page.has_more);
+
+        let page = list_users_paged(&mut *tx, 2, 2).await?;
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.total, 3);
+        assert!(!page.has_more);
+
+        let json = serde_json::to_value(&page)?;
+        assert_eq!(json["total"], 3);
+        assert_eq!(json["items"][0]["name"], "John Doe");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn search_users_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut tx = begin_with_empty_tables(&pool).await?;
+        let user_id = insert_user(&mut *tx, "John Doe", &unique_email("user1")).await?;
+        insert_user(&mut *tx, "Jane Roe", &unique_email("user2")).await?;
+
+        for query in ["doe", "DOE", "john doe", "Doe John"] {
+            let users = search_users(&mut *tx, query).await?;
+            let ids: Vec<i32> = users.iter().map(|user| user.id).collect();
+            assert_eq!(ids, vec![user_id], "query: {query}");
+        }
+        assert!(search_users(&mut *tx, "john roe").await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_by_name_prefix_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut tx = begin_with_empty_tables(&pool).await?;
+        let john_id = insert_user(&mut *tx, "John Doe", &unique_email("user1")).await?;
+        let abuse_id = insert_user(&mut *tx, "% wildcard abuse", &unique_email("user2")).await?;
+        let backslash_id = insert_user(&mut *tx, "\\_o", &unique_email("user3")).await?;
+
+        let ids = |users: Vec<User>| users.iter().map(|user| user.id).collect::<Vec<_>>();
+        assert_eq!(
+            ids(users_by_name_prefix(&mut *tx, "jo", 10).await?),
+            vec![john_id]
+        );
+        assert_eq!(
+            ids(users_by_name_prefix(&mut *tx, "%", 10).await?),
+            vec![abuse_id]
+        );
+        assert_eq!(
+            ids(users_by_name_prefix(&mut *tx, "\\_", 10).await?),
+            vec![backslash_id]
+        );
+        assert!(users_by_name_prefix(&mut *tx, "_", 10).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_users_negative_limit() -> Result<()> {
+        let pool = connect_postgres().await?;
+        insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+
+        let users = list_users(&pool, -1, -1).await?;
+
+        assert!(users.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_users_after_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut tx = begin_with_empty_tables(&pool).await?;
+        let mut user_ids = vec![];
+        for i in 0..5 {
+            user_ids
+                .push(insert_user(&mut *tx, "John Doe", &unique_email(&format!("user{i}"))).await?);
+        }
+
+        let mut paged_ids = vec![];
+        let mut after_id = None;
+        loop {
+            let users = list_users_after(&mut *tx, after_id, 2).await?;
+            let Some(last) = users.last() else {
+                break;
+            };
+            after_id = Some(last.id);
+            paged_ids.extend(users.iter().map(|user| user.id));
+        }
+
+        assert_eq!(paged_ids, user_ids);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn count_users_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut tx = begin_with_empty_tables(&pool).await?;
+
+        assert_eq!(count_users(&mut *tx).await?, 0);
+
+        insert_user(&mut *tx, "John Doe", &unique_email("hoge")).await?;
+        assert_eq!(count_users(&mut *tx).await?, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_registration_ip_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        assert_eq!(
+            find_user(&pool, user_id).await?.unwrap().registration_ip,
+            None
+        );
+
+        for ip in ["192.168.1.1", "2001:db8::1"] {
+            let ip = IpAddr::from_str(ip)?;
+
+            set_registration_ip(&pool, user_id, ip).await?;
+
+            let user = find_user(&pool, user_id).await?.unwrap();
+            assert_eq!(user.registration_ip, Some(ip));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_user_address_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        let address = Address {
+            street: "1-2-3 Shibuya, \"Room 4\"".to_string(),
+            city: "Tokyo".to_string(),
+        };
+
+        set_user_address(&pool, user_id, &address).await?;
+
+        let user = find_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.address, Some(address));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_avatar_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        let bytes: Vec<u8> = (0..=u8::MAX).collect();
+
+        set_avatar(&pool, user_id, &bytes).await?;
+
+        let user = find_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.avatar, Some(bytes));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn session_length_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        assert_eq!(session_length(&pool, user_id).await?, None);
+
+        sqlx::query("UPDATE users SET session_length = '2 hours' WHERE id = $1")
+            .bind(user_id)
+            .execute(&pool)
+            .await?;
+        assert_eq!(
+            session_length(&pool, user_id).await?,
+            Some(Duration::from_secs(7200))
+        );
+
+        set_session_length(&pool, user_id, Duration::from_millis(90_500)).await?;
+        assert_eq!(
+            session_length(&pool, user_id).await?,
+            Some(Duration::from_millis(90_500))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_session_length_truncates_nanos() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+
+        set_session_length(&pool, user_id, Duration::from_nanos(1_500)).await?;
+
+        assert_eq!(
+            session_length(&pool, user_id).await?,
+            Some(Duration::from_micros(1))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn session_length_with_months() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        sqlx::query("UPDATE users SET session_length = '1 mon' WHERE id = $1")
+            .bind(user_id)
+            .execute(&pool)
+            .await?;
+
+        let res = session_length(&pool, user_id).await;
+
+        assert!(res.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn session_length_overflow() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        sqlx::query("UPDATE users SET session_length = '2147483647 days' WHERE id = $1")
+            .bind(user_id)
+            .execute(&pool)
+            .await?;
+
+        let res = session_length(&pool, user_id).await;
+
+        assert!(res.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn insert_users_values_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let users: Vec<(String, Email)> = (0..3)
+            .map(|i| (format!("User {i}"), unique_email(&format!("user{i}"))))
+            .collect();
+
+        let user_ids = insert_users_values(&pool, &users).await?;
+
+        assert_eq!(user_ids.len(), users.len());
+        for (user_id, (name, email)) in user_ids.into_iter().zip(users) {
+            let user = find_user(&pool, user_id).await?.unwrap();
+            assert_eq!(user.name, name);
+            assert_eq!(user.email, email.as_str());
+        }
+
+        assert_eq!(insert_users_values(&pool, &[]).await?, Vec::<i32>::new());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn insert_users_lenient_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+        let users = vec![
+            ("John Doe".to_string(), email.clone()),
+            ("Jane Doe".to_string(), unique_email("fuga")),
+            ("Jim Doe".to_string(), email),
+        ];
+
+        let outcomes = insert_users_lenient(&pool, &users).await?;
+
+        assert_eq!(outcomes.len(), 3);
+        let john_id = *outcomes[0].as_ref().unwrap();
+        let jane_id = *outcomes[1].as_ref().unwrap();
+        assert!(matches!(outcomes[2], Err(UserError::DuplicateEmail)));
+        assert_eq!(find_user(&pool, john_id).await?.unwrap().name, "John Doe");
+        assert_eq!(find_user(&pool, jane_id).await?.unwrap().name, "Jane Doe");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn insert_users_bulk_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let users: Vec<(String, Email)> = (0..3)
+            .map(|i| (format!("User {i}"), unique_email(&format!("user{i}"))))
+            .collect();
+
+        let user_ids = insert_users_bulk(&pool, &users).await?;
+
+        assert_eq!(user_ids.len(), users.len());
+        for (user_id, (name, email)) in user_ids.into_iter().zip(users) {
+            let user = find_user(&pool, user_id).await?.unwrap();
+            assert_eq!(user.name, name);
+            assert_eq!(user.email, email.as_str());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn insert_users_bulk_empty() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let user_ids = insert_users_bulk(&pool, &[]).await?;
+
+        assert!(user_ids.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn copy_in_users_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let rows = (0..1000).map(|i| (format!("User {i}"), unique_email(&format!("user{i}"))));
+
+        let count = copy_in_users(&pool, rows).await?;
+
+        assert_eq!(count, 1000);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn copy_in_users_escaping() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let name = "Doe, \"Johnny\"\nJohn";
+        let email = unique_email("hoge");
+
+        copy_in_users(&pool, [(name.to_string(), email.clone())].into_iter()).await?;
+
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
+            .bind(&email)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(user.name, name);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn insert_user_uuid_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let id = Uuid::new_v4();
+        let email = unique_email("hoge");
+
+        let inserted_id = insert_user_uuid(&pool, id, "John Doe", &email).await?;
+
+        assert_eq!(inserted_id, id);
+        let user = find_user_uuid(&pool, id).await?.unwrap();
+        assert_eq!(
+            user,
+            UserUuid {
+                id,
+                name: "John Doe".to_string(),
+                email: email.to_string(),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_with_posts_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut tx = begin_with_empty_tables(&pool).await?;
+        let user_id_1 = insert_user(&mut *tx, "John Doe", &unique_email("user1")).await?;
+        let user_id_2 = insert_user(&mut *tx, "Jane Doe", &unique_email("user2")).await?;
+        let post_id = insert_post(&mut *tx, user_id_1, "body").await?;
+
+        let users = users_with_posts(&mut *tx).await?;
+
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].id, user_id_1);
+        assert_eq!(
+            users[0].posts,
+            vec![Post {
+                id: post_id,
+                user_id: user_id_1,
+                content: "body".to_string(),
+            }]
+        );
+        assert_eq!(users[1].id, user_id_2);
+        assert_eq!(users[1].posts, vec![]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_user_with_post_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        let post_id = insert_post(&pool, user_id, "body").await?;
+
+        let user_with_post = find_user_with_post(&pool, post_id).await?.unwrap();
+
+        assert_eq!(user_with_post.user.id, user_id);
+        assert_eq!(user_with_post.user.name, "John Doe");
+        assert_eq!(
+            user_with_post.post,
+            Post {
+                id: post_id,
+                user_id,
+                content: "body".to_string(),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn posts_repository_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        let repo = PostsRepository::new(pool);
+
+        let post_id_1 = repo.insert(user_id, "first").await?;
+        let post_id_2 = repo.insert(user_id, "second").await?;
+
+        let posts = repo.by_user(user_id).await?;
+        assert_eq!(
+            posts,
+            vec![
+                Post {
+                    id: post_id_1,
+                    user_id,
+                    content: "first".to_string(),
+                },
+                Post {
+                    id: post_id_2,
+                    user_id,
+                    content: "second".to_string(),
+                },
+            ]
+        );
+
+        assert!(repo.delete(post_id_1).await?);
+        assert!(!repo.delete(post_id_1).await?);
+        assert_eq!(repo.by_user(user_id).await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn post_counts_by_user_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        insert_post(&pool, user_id, "first").await?;
+        insert_post(&pool, user_id, "second").await?;
+        let no_posts_id = insert_user(&pool, "Jane Doe", &unique_email("fuga")).await?;
+
+        let counts = post_counts_by_user(&pool).await?;
+        assert!(counts.contains(&(user_id, 2)));
+        assert!(counts.iter().all(|(id, _)| *id != no_posts_id));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn search_posts_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        let post_id = insert_post(&pool, user_id, "hello world").await?;
+
+        let posts = search_posts(&pool, "helo", 0.2).await?;
+        assert!(posts.iter().any(|post| post.id == post_id));
+
+        let posts = search_posts(&pool, "helo", 0.9).await?;
+        assert!(posts.iter().all(|post| post.id != post_id));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tx_repository_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut tx = pool.begin().await?;
+
+        let mut repo = TxRepository::new(&mut tx);
+        let user_id = repo.insert("John Doe", &unique_email("hoge")).await?;
+        let post_id = repo.insert_post(user_id, "body").await?;
+        repo.update(user_id, "Jane Doe", &unique_email("fuga"))
+            .await?;
+        assert_eq!(repo.find(user_id).await?.unwrap().name, "Jane Doe");
+
+        tx.commit().await?;
+
+        assert_eq!(find_user(&pool, user_id).await?.unwrap().name, "Jane Doe");
+        let posts = posts_by_user(&pool, user_id).await?;
+        assert_eq!(
+            posts.iter().map(|post| post.id).collect::<Vec<_>>(),
+            vec![post_id]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tx_repository_in_savepoint() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut tx = pool.begin().await?;
+        let email = unique_email("hoge");
+
+        let res: Result<()> = crate::transaction::with_savepoint(&mut tx, |savepoint| {
+            let email = email.clone();
+            Box::pin(async move {
+                TxRepository::new(savepoint)
+                    .insert("John Doe", &email)
+                    .await?;
+                bail!("abort")
+            })
+        })
+        .await;
+        assert!(res.is_err());
+
+        assert_eq!(find_user_by_email(&mut *tx, email.as_str()).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn current_subscriptions_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let user_id = insert_user(&pool, "John Doe", &unique_email("hoge")).await?;
+        // Postgres keeps microseconds, so round-tripped bounds lose nanoseconds.
+        let now = Utc::now().trunc_subsecs(6);
+        let day = chrono::Duration::days(1);
+
+        let current = PgRange::from(now - day..now + day);
+        let current_id = insert_subscription(&pool, user_id, current.clone()).await?;
+        insert_subscription(&pool, user_id, PgRange::from(now - day * 2..now - day)).await?;
+
+        let subscriptions = current_subscriptions(&pool, user_id).await?;
+
+        assert_eq!(
+            subscriptions,
+            vec![Subscription {
+                id: current_id,
+                user_id,
+                valid_during: current,
+            }]
+        );
+
+        Ok(())
+    }
+}
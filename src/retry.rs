@@ -0,0 +1,134 @@
+use std::{future::Future, time::Duration};
+
+use anyhow::Result;
+
+use crate::telemetry::SqlxSource;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Runs `f` up to `attempts` times, retrying with exponential backoff while it
+/// fails with a transient error (see [`is_transient`]). Any other error is
+/// returned right away.
+pub async fn with_retry<F, Fut, T>(attempts: u32, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < attempts && is_transient(&err) => {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Whether `err` comes from losing the connection or the pool rather than
+/// from the query itself, so running it again may succeed.
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    let Some(err) = err.sqlx_error() else {
+        return false;
+    };
+
+    match err {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed => true,
+        // Class 08 is connection exceptions, 57P0x is the server shutting down.
+        sqlx::Error::Database(err) => err
+            .code()
+            .is_some_and(|code| code.starts_with("08") || code.starts_with("57P0")),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        repository::insert_user,
+        test_utils::{connect_postgres, unique_email},
+    };
+
+    #[tokio::test]
+    async fn with_retry_transient() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+        let mut calls = 0;
+
+        let user_id = with_retry(5, || {
+            calls += 1;
+            let fail = calls <= 2;
+            let (pool, email) = (&pool, &email);
+            async move {
+                if fail {
+                    return Err(sqlx::Error::PoolTimedOut.into());
+                }
+                Ok(insert_user(pool, "John Doe", email).await?)
+            }
+        })
+        .await?;
+
+        assert!(user_id > 0);
+        assert_eq!(calls, 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn with_retry_gives_up() -> Result<()> {
+        let mut calls = 0;
+
+        let res = with_retry(2, || {
+            calls += 1;
+            async { Err::<(), _>(sqlx::Error::PoolTimedOut.into()) }
+        })
+        .await;
+
+        assert!(res.is_err());
+        assert_eq!(calls, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn with_retry_not_transient() -> Result<()> {
+        let mut calls = 0;
+
+        let res = with_retry(5, || {
+            calls += 1;
+            async { Err::<(), _>(sqlx::Error::RowNotFound.into()) }
+        })
+        .await;
+
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn with_retry_constraint_violation() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+        insert_user(&pool, "John Doe", &email).await?;
+        let mut calls = 0;
+
+        let res = with_retry(5, || {
+            calls += 1;
+            let (pool, email) = (&pool, &email);
+            async move { Ok(insert_user(pool, "John Doe", email).await?) }
+        })
+        .await;
+
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use anyhow::Result;
+use sqlx::{PgExecutor, Pool, Postgres};
+
+/// Applies the migrations in `migrations/` that have not run yet.
+pub async fn run_migrations(pool: &Pool<Postgres>) -> Result<()> {
+    sqlx::migrate!().run(pool).await?;
+
+    Ok(())
+}
+
+/// Empties `users`, `posts`, `subscriptions`, `events` and `users_uuid`, and
+/// restarts their ids at 1. Safe to call repeatedly.
+///
+/// Without `CASCADE`, so a table added later with a foreign key to one of
+/// these makes this fail until it is listed here too, instead of being
+/// emptied behind the caller's back.
+pub async fn reset_schema(executor: impl PgExecutor<'_>) -> Result<()> {
+    sqlx::query("TRUNCATE users, posts, subscriptions, events, users_uuid RESTART IDENTITY")
+        .execute(executor)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connect_postgres,
+        repository::{count_users, insert_user},
+        test_utils::unique_email,
+    };
+
+    #[tokio::test]
+    async fn run_migrations_twice() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        run_migrations(&pool).await?;
+        run_migrations(&pool).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reset_schema_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        run_migrations(&pool).await?;
+        // Reset inside a transaction that is rolled back, so tests running
+        // concurrently keep their rows.
+        let mut tx = pool.begin().await?;
+
+        reset_schema(&mut *tx).await?;
+        reset_schema(&mut *tx).await?;
+
+        assert_eq!(count_users(&mut *tx).await?, 0);
+        let events: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM events")
+            .fetch_one(&mut *tx)
+            .await?;
+        assert_eq!(events, 0);
+        let user_id = insert_user(&mut *tx, "John Doe", &unique_email("hoge")).await?;
+        assert_eq!(user_id, 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use std::{future::Future, time::Instant};
+
+use sqlx::error::ErrorKind;
+
+use crate::{error::UserError, metrics::Metrics};
+
+/// Awaits a repository operation inside the caller's span, logging how long
+/// it took and how many rows it touched at debug level, or the failure at
+/// error level, and records it in `metrics` under `operation`, the name of
+/// the caller's span.
+pub(crate) async fn traced<T, E>(
+    metrics: &Metrics,
+    operation: &'static str,
+    fut: impl Future<Output = Result<T, E>>,
+) -> Result<T, E>
+where
+    T: Rows,
+    E: SqlxSource + std::fmt::Display,
+{
+    let start = Instant::now();
+    let res = fut.await;
+    let elapsed = start.elapsed();
+    let elapsed_ms = elapsed.as_secs_f64() * 1000.0;
+    metrics.record(operation, elapsed, res.is_err());
+
+    match &res {
+        Ok(value) => tracing::debug!(elapsed_ms, rows = value.rows(), "query finished"),
+        Err(err) => tracing::error!(
+            elapsed_ms,
+            kind = err.sqlx_error().map_or("other", error_kind),
+            error = %err,
+            "query failed"
+        ),
+    }
+
+    res
+}
+
+/// How many rows a repository operation returned or affected.
+pub(crate) trait Rows {
+    fn rows(&self) -> u64;
+}
+
+impl Rows for u64 {
+    fn rows(&self) -> u64 {
+        *self
+    }
+}
+
+/// Deletes and other yes/no writes touch at most one row.
+impl Rows for bool {
+    fn rows(&self) -> u64 {
+        u64::from(*self)
+    }
+}
+
+/// Inserted ids and counts come back as a single row.
+impl Rows for i32 {
+    fn rows(&self) -> u64 {
+        1
+    }
+}
+
+impl Rows for i64 {
+    fn rows(&self) -> u64 {
+        1
+    }
+}
+
+impl<T> Rows for Option<T> {
+    fn rows(&self) -> u64 {
+        u64::from(self.is_some())
+    }
+}
+
+impl<T> Rows for Vec<T> {
+    fn rows(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+/// Digs the underlying [`sqlx::Error`] out of a repository error, if any.
+pub(crate) trait SqlxSource {
+    fn sqlx_error(&self) -> Option<&sqlx::Error>;
+}
+
+impl SqlxSource for anyhow::Error {
+    fn sqlx_error(&self) -> Option<&sqlx::Error> {
+        match self.downcast_ref::<UserError>() {
+            Some(err) => err.sqlx_error(),
+            None => self.downcast_ref::<sqlx::Error>(),
+        }
+    }
+}
+
+impl SqlxSource for UserError {
+    fn sqlx_error(&self) -> Option<&sqlx::Error> {
+        match self {
+            UserError::Database(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+fn error_kind(err: &sqlx::Error) -> &'static str {
+    match err {
+        sqlx::Error::Database(err) => match err.kind() {
+            ErrorKind::UniqueViolation => "unique_violation",
+            ErrorKind::ForeignKeyViolation => "foreign_key_violation",
+            ErrorKind::NotNullViolation => "not_null_violation",
+            ErrorKind::CheckViolation => "check_violation",
+            _ => "database",
+        },
+        sqlx::Error::RowNotFound => "row_not_found",
+        sqlx::Error::ColumnDecode { .. } | sqlx::Error::Decode(_) => "decode",
+        sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::Protocol(_) => "connection",
+        sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed => "pool",
+        _ => "other",
+    }
+}
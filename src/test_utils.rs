@@ -0,0 +1,67 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use sqlx::{Pool, Postgres, Transaction};
+use tokio::sync::OnceCell;
+
+use crate::{
+    email::Email,
+    schema::{reset_schema, run_migrations},
+};
+
+/// Same as [`crate::connect_postgres`], but makes sure the schema is migrated
+/// first, so tests run against a freshly created database.
+pub async fn connect_postgres() -> Result<Pool<Postgres>> {
+    static MIGRATED: OnceCell<()> = OnceCell::const_new();
+
+    let pool = crate::connect_postgres().await?;
+    MIGRATED.get_or_try_init(|| run_migrations(&pool)).await?;
+
+    Ok(pool)
+}
+
+/// Returns an email that no other test has inserted, since `users.email` is
+/// unique and tests share one database.
+pub fn unique_email(local: &str) -> Email {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    Email::try_from(format!("{local}+{nanos}.{count}@example.com").as_str()).unwrap()
+}
+
+/// Begins a transaction that sees the tables [`reset_schema`] empties as
+/// empty.
+///
+/// The reset is rolled back together with the transaction, so other tests
+/// only ever wait on the table lock and never lose their rows.
+pub async fn begin_with_empty_tables(pool: &Pool<Postgres>) -> Result<Transaction<'_, Postgres>> {
+    let mut tx = pool.begin().await?;
+
+    reset_schema(&mut *tx).await?;
+
+    Ok(tx)
+}
+
+/// Runs a test body inside a transaction that is always rolled back, so
+/// nothing it writes outlives the test.
+pub async fn test_in_transaction<F>(f: F) -> Result<()>
+where
+    F: for<'a> FnOnce(&'a mut Transaction<'static, Postgres>) -> BoxFuture<'a, Result<()>>,
+{
+    let pool = connect_postgres().await?;
+    let mut tx = pool.begin().await?;
+
+    let res = f(&mut tx).await;
+    tx.rollback().await?;
+
+    res
+}
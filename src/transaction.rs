@@ -0,0 +1,428 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use sqlx::{Acquire, PgConnection, Pool, Postgres, Transaction};
+
+use crate::telemetry::SqlxSource;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl IsolationLevel {
+    fn as_sql(self) -> &'static str {
+        match self {
+            Self::ReadCommitted => "READ COMMITTED",
+            Self::RepeatableRead => "REPEATABLE READ",
+            Self::Serializable => "SERIALIZABLE",
+        }
+    }
+}
+
+/// Begins a transaction running at `level` instead of the server default.
+pub async fn begin_with_isolation(
+    pool: &Pool<Postgres>,
+    level: IsolationLevel,
+) -> Result<Transaction<'static, Postgres>> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(&format!(
+        "SET TRANSACTION ISOLATION LEVEL {}",
+        level.as_sql()
+    ))
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(tx)
+}
+
+/// Runs `f` inside a transaction, committing when it returns `Ok` and rolling
+/// back when it returns `Err`.
+pub async fn with_transaction<F, T>(pool: &Pool<Postgres>, f: F) -> Result<T>
+where
+    F: for<'a> FnOnce(&'a mut Transaction<'static, Postgres>) -> BoxFuture<'a, Result<T>>,
+{
+    let mut tx = pool.begin().await?;
+
+    match f(&mut tx).await {
+        Ok(value) => {
+            tx.commit().await?;
+            Ok(value)
+        }
+        Err(err) => rollback_after(tx, err).await,
+    }
+}
+
+/// Same as [`with_transaction`], but runs `f` again in a fresh transaction
+/// when Postgres aborted it to break a deadlock (SQLSTATE `40P01`), up to
+/// `attempts` times in total. Any other error is returned right away.
+pub async fn with_deadlock_retry<F, T>(pool: &Pool<Postgres>, attempts: u32, mut f: F) -> Result<T>
+where
+    F: for<'a> FnMut(&'a mut Transaction<'static, Postgres>) -> BoxFuture<'a, Result<T>>,
+{
+    let mut attempt = 1;
+
+    loop {
+        match with_transaction(pool, |tx| f(tx)).await {
+            Err(err) if attempt < attempts && is_deadlock(&err) => attempt += 1,
+            res => return res,
+        }
+    }
+}
+
+fn is_deadlock(err: &anyhow::Error) -> bool {
+    err.sqlx_error()
+        .and_then(|err| err.as_database_error())
+        .and_then(|err| err.code())
+        .is_some_and(|code| code == "40P01")
+}
+
+/// Rolls `tx` back after `err` and returns `err`, even when the rollback fails
+/// too, e.g. because the connection is gone, so the actual cause is not lost.
+async fn rollback_after<T>(tx: Transaction<'_, Postgres>, err: anyhow::Error) -> Result<T> {
+    if let Err(rollback) = tx.rollback().await {
+        tracing::warn!(error = %rollback, "rollback failed");
+    }
+
+    Err(err)
+}
+
+/// Runs `f` inside a savepoint of `tx`. When `f` fails only its own changes
+/// are rolled back, so the outer transaction can still commit.
+pub async fn with_savepoint<F, T>(tx: &mut Transaction<'_, Postgres>, f: F) -> Result<T>
+where
+    F: for<'a, 'b> FnOnce(&'a mut Transaction<'b, Postgres>) -> BoxFuture<'a, Result<T>>,
+{
+    // A transaction begun on a transaction is a savepoint, which sqlx names
+    // after its depth, so these can nest.
+    let mut savepoint = tx.begin().await?;
+
+    match f(&mut savepoint).await {
+        Ok(value) => {
+            savepoint.commit().await?;
+            Ok(value)
+        }
+        Err(err) => rollback_after(savepoint, err).await,
+    }
+}
+
+/// Runs `f` inside a transaction whose statements are cancelled by the server
+/// once they run longer than `timeout`, failing with SQLSTATE `57014`
+/// (`query_canceled`).
+///
+/// `timeout` is rounded up to whole milliseconds, and is at least 1ms, since a
+/// `statement_timeout` of `0` would disable the limit instead.
+pub async fn with_statement_timeout<'c, F, T>(
+    conn: &'c mut PgConnection,
+    timeout: Duration,
+    f: F,
+) -> Result<T>
+where
+    F: for<'a> FnOnce(&'a mut Transaction<'c, Postgres>) -> BoxFuture<'a, Result<T>>,
+{
+    let mut tx = conn.begin().await?;
+
+    // Same as `SET LOCAL statement_timeout`, which cannot take a bind parameter.
+    sqlx::query("SELECT set_config('statement_timeout', $1, true)")
+        .bind(timeout.as_nanos().div_ceil(1_000_000).max(1).to_string())
+        .execute(&mut *tx)
+        .await?;
+
+    match f(&mut tx).await {
+        Ok(value) => {
+            tx.commit().await?;
+            Ok(value)
+        }
+        Err(err) => rollback_after(tx, err).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::{
+        repository::{find_user, insert_post, insert_user},
+        test_utils::{connect_postgres, unique_email},
+    };
+
+    #[tokio::test]
+    async fn with_transaction_commit() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+
+        let user_id = with_transaction(&pool, |tx| {
+            Box::pin(async move {
+                let user_id = insert_user(&mut **tx, "John Doe", &email).await?;
+                insert_post(&mut **tx, user_id, "body").await?;
+                Ok(user_id)
+            })
+        })
+        .await?;
+
+        assert!(find_user(&pool, user_id).await?.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn with_transaction_rollback() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email = unique_email("hoge");
+
+        let res = with_transaction(&pool, |tx| {
+            let email = email.clone();
+            Box::pin(async move {
+                insert_user(&mut **tx, "John Doe", &email).await?;
+                Err::<(), _>(anyhow!("abort"))
+            })
+        })
+        .await;
+
+        assert!(res.is_err());
+        let row: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE email = $1")
+            .bind(&email)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.0, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn with_transaction_rollback_fails() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let res = with_transaction(&pool, |tx| {
+            Box::pin(async move {
+                // Kills the connection, so the rollback that follows fails.
+                let _ = sqlx::query("SELECT pg_terminate_backend(pg_backend_pid())")
+                    .execute(&mut **tx)
+                    .await;
+                Err::<(), _>(anyhow!("abort"))
+            })
+        })
+        .await;
+
+        assert_eq!(res.unwrap_err().to_string(), "abort");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn with_savepoint_partial_rollback() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let email_1 = unique_email("user1");
+        let email_3 = unique_email("user3");
+        let emails = [email_1.clone(), email_1.clone(), email_3.clone()];
+
+        let mut tx = pool.begin().await?;
+        let mut results = vec![];
+        for email in emails {
+            let res = with_savepoint(&mut tx, |tx| {
+                Box::pin(async move { Ok(insert_user(&mut **tx, "John Doe", &email).await?) })
+            })
+            .await;
+            results.push(res);
+        }
+        tx.commit().await?;
+
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        assert!(results[2].is_ok());
+        let row: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE email = ANY($1)")
+            .bind(vec![email_1, email_3])
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.0, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn begin_with_isolation_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+
+        let mut tx = begin_with_isolation(&pool, IsolationLevel::RepeatableRead).await?;
+
+        let row: (String,) = sqlx::query_as("SELECT current_setting('transaction_isolation')")
+            .fetch_one(&mut *tx)
+            .await?;
+        assert_eq!(row.0, "repeatable read");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn begin_with_isolation_serialization_failure() -> Result<()> {
+        async fn insert_named(tx: &mut Transaction<'_, Postgres>, name: &str) -> Result<()> {
+            sqlx::query("INSERT INTO users (name, email) VALUES ($1, $2)")
+                .bind(name)
+                .bind(unique_email("hoge"))
+                .execute(&mut **tx)
+                .await?;
+            Ok(())
+        }
+
+        let pool = connect_postgres().await?;
+        let name = unique_email("write-skew").to_string();
+        let mut tx_1 = begin_with_isolation(&pool, IsolationLevel::Serializable).await?;
+        let mut tx_2 = begin_with_isolation(&pool, IsolationLevel::Serializable).await?;
+
+        // Both transactions read the same predicate before writing into it,
+        // which is a write skew that only SERIALIZABLE detects.
+        for tx in [&mut tx_1, &mut tx_2] {
+            sqlx::query("SELECT COUNT(*) FROM users WHERE name = $1")
+                .bind(&name)
+                .fetch_one(&mut **tx)
+                .await?;
+        }
+        insert_named(&mut tx_1, &name).await?;
+        insert_named(&mut tx_2, &name).await?;
+        tx_1.commit().await?;
+        let res = tx_2.commit().await;
+
+        assert!(
+            matches!(res, Err(sqlx::Error::Database(err)) if err.code().as_deref() == Some("40001"))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn with_statement_timeout_cancels() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut conn = pool.acquire().await?;
+        let started = Instant::now();
+
+        let res = with_statement_timeout(&mut conn, Duration::from_millis(500), |tx| {
+            Box::pin(async move {
+                sqlx::query("SELECT pg_sleep(5)").execute(&mut **tx).await?;
+                Ok(())
+            })
+        })
+        .await;
+
+        assert!(started.elapsed() < Duration::from_secs(3));
+        let err = res.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<sqlx::Error>(),
+            Some(sqlx::Error::Database(err)) if err.code().as_deref() == Some("57014")
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn with_statement_timeout_ok() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut conn = pool.acquire().await?;
+
+        let value = with_statement_timeout(&mut conn, Duration::from_secs(2), |tx| {
+            Box::pin(async move {
+                let row: (String,) = sqlx::query_as("SELECT current_setting('statement_timeout')")
+                    .fetch_one(&mut **tx)
+                    .await?;
+                Ok(row.0)
+            })
+        })
+        .await?;
+
+        assert_eq!(value, "2s");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn with_statement_timeout_zero() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut conn = pool.acquire().await?;
+
+        let value = with_statement_timeout(&mut conn, Duration::ZERO, |tx| {
+            Box::pin(async move {
+                let row: (String,) = sqlx::query_as("SELECT current_setting('statement_timeout')")
+                    .fetch_one(&mut **tx)
+                    .await?;
+                Ok(row.0)
+            })
+        })
+        .await?;
+
+        assert_eq!(value, "1ms");
+
+        Ok(())
+    }
+
+    const RAISE_DEADLOCK: &str =
+        "DO $$ BEGIN RAISE EXCEPTION 'synthetic deadlock' USING ERRCODE = 'deadlock_detected'; END $$";
+
+    #[tokio::test]
+    async fn with_deadlock_retry_retries_deadlock() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut calls = 0;
+
+        let value = with_deadlock_retry(&pool, 3, |tx| {
+            calls += 1;
+            let first = calls == 1;
+            Box::pin(async move {
+                if first {
+                    sqlx::query(RAISE_DEADLOCK).execute(&mut **tx).await?;
+                }
+                Ok(42)
+            })
+        })
+        .await?;
+
+        assert_eq!(value, 42);
+        assert_eq!(calls, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn with_deadlock_retry_gives_up() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut calls = 0;
+
+        let res = with_deadlock_retry(&pool, 3, |tx| {
+            calls += 1;
+            Box::pin(async move {
+                sqlx::query(RAISE_DEADLOCK).execute(&mut **tx).await?;
+                Ok(())
+            })
+        })
+        .await;
+
+        assert!(is_deadlock(&res.unwrap_err()));
+        assert_eq!(calls, 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn with_deadlock_retry_other_error() -> Result<()> {
+        let pool = connect_postgres().await?;
+        let mut calls = 0;
+
+        let res = with_deadlock_retry(&pool, 3, |tx| {
+            calls += 1;
+            Box::pin(async move {
+                sqlx::query("SELECT 1 / 0").execute(&mut **tx).await?;
+                Ok(())
+            })
+        })
+        .await;
+
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+
+        Ok(())
+    }
+}